@@ -1,77 +1,690 @@
 use std::{
-    error,
-    io::{self, Read},
-    net::{IpAddr, SocketAddr, UdpSocket},
-    path::Path,
+    env, error, fs,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
+    path::{Path, PathBuf},
+    process,
     str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand};
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
 
-use dns_camo::dns_packet::{Packet, RecordType};
-use dns_camo::payload::Payload;
+use dns_camo::client::{Client, ClientError};
+use dns_camo::codec::{self, LabelCodec};
+use dns_camo::dns_packet::{DnsName, RecordType, MAX_EDNS_UDP_LEN};
+use dns_camo::doh::{self, DohError, DohUrl, Method};
+use dns_camo::keys::{self, Key, KeyDerivation, KeyError, KeyFormat};
+use dns_camo::relay::{self, Relay, Request};
+use dns_camo::socks::{self, Credentials};
+use dns_camo::tcp;
+use dns_camo::tls::{self, Pin};
+
+// How long to wait for each response, and how many to miss in a row before
+// giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_FAILURES: u32 = 10;
+// How long SOCKS clients get for each step of the handshake
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to key file
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a message to the server
+    Send {
+        /// String to be send [default: read from stdin]
+        #[arg(long)]
+        data: Option<String>,
+
+        /// Keep polling and print messages from the server until interrupted
+        #[arg(short, long)]
+        listen: bool,
+
+        #[command(flatten)]
+        tunnel: TunnelArgs,
+    },
+    /// Forward connections to a local port to a host reachable from the server
+    Forward {
+        /// [BIND_ADDRESS:]PORT:HOST:HOSTPORT, as with ssh -L
+        #[arg(short = 'L', long = "local")]
+        spec: ForwardSpec,
+
+        #[command(flatten)]
+        tunnel: TunnelArgs,
+    },
+    /// Run a SOCKS5 proxy connecting to hosts reachable from the server
+    Socks {
+        /// Local address to accept SOCKS connections on
+        #[arg(short = 'D', long, default_value = "127.0.0.1:1080")]
+        bind: SocketAddr,
+
+        /// Username SOCKS clients must authenticate with
+        #[arg(long, requires = "password_file")]
+        username: Option<String>,
+
+        /// Path to a file holding the password SOCKS clients must
+        /// authenticate with, on its first line
+        #[arg(long, requires = "username", value_name = "FILE", value_parser = parse_password)]
+        password_file: Option<String>,
+
+        #[command(flatten)]
+        tunnel: TunnelArgs,
+    },
+    /// Write a new key file, to give the server too
+    Keygen {
+        /// Path of the key file, which mustn't exist yet
+        file: PathBuf,
+
+        /// Format of the key file: base64 after a header line, or raw, the 32
+        /// bytes of the key
+        #[arg(long, default_value = "base64")]
+        format: KeyFormat,
+
+        /// Write only a salt, and derive the key from it and the passphrase in
+        /// DNS_CAMO_PASSPHRASE whenever the file is loaded
+        #[arg(long, conflicts_with = "format")]
+        passphrase: bool,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+#[command(group = ArgGroup::new("tls").args(["doh", "dot"]))]
+struct TunnelArgs {
+    /// Path to key file, as written by keygen. Keys derived from a passphrase
+    /// take it from DNS_CAMO_PASSPHRASE
+    #[arg(short, long, value_parser = parse_key)]
+    key: Key,
+
+    /// Tunnel domain the server is delegated, e.g. t.example.org
     #[arg(short, long)]
-    key: String,
+    domain: DnsName,
+
+    /// Record type for the server's answers, e.g. TXT [default: the best one
+    /// found to make it through]
+    #[arg(long, value_name = "TYPE")]
+    record_type: Option<RecordType>,
+
+    /// Encoding of data in names: hex, base32, base36, base64 or raw. The
+    /// last two need a path which keeps the case and bytes of names
+    #[arg(long, default_value = "base32", value_parser = parse_codec)]
+    codec: &'static dyn LabelCodec,
 
-    /// String to be send
+    /// Largest response to take, advertised with EDNS(0). 512 leaves EDNS out
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = MAX_EDNS_UDP_LEN as u16,
+        value_parser = clap::value_parser!(u16).range(512..=MAX_EDNS_UDP_LEN as i64),
+    )]
+    udp_size: u16,
+
+    /// Send every query over TCP, instead of only those whose responses
+    /// came back truncated over UDP
     #[arg(long)]
-    data: Option<String>,
+    tcp: bool,
+
+    /// Send queries to this DNS over HTTPS resolver instead, e.g.
+    /// https://dns.example.net/dns-query
+    #[arg(long, value_name = "URL", conflicts_with = "tcp")]
+    doh: Option<DohUrl>,
+
+    /// HTTP method of DNS over HTTPS queries, GET or POST
+    #[arg(long, value_name = "METHOD", default_value = "post")]
+    doh_method: Method,
 
-    /// Server IP address
-    dest: String,
+    /// Send queries over DNS over TLS to this server or resolver instead
+    #[arg(long, value_name = "HOST[:PORT]", conflicts_with = "tcp")]
+    dot: Option<DotServer>,
+
+    /// PEM file with the certificates to trust instead of the usual roots,
+    /// e.g. the server's self-signed one
+    #[arg(long, value_name = "PEM", requires = "tls")]
+    ca_cert: Option<PathBuf>,
+
+    /// Trust servers presenting a certificate for this public key instead,
+    /// whoever signed it. The server prints the pin of its own
+    #[arg(
+        long,
+        value_name = "sha256/BASE64",
+        requires = "tls",
+        conflicts_with = "ca_cert"
+    )]
+    pin: Vec<Pin>,
+
+    /// Server or recursive resolver IP address [default: system resolver]
+    dest: Option<IpAddr>,
+
+    /// Server or recursive resolver port
+    #[arg(default_value_t = 53)]
+    port: u16,
+}
 
-    /// Server listening port
+#[derive(Debug, Clone)]
+struct ForwardSpec {
+    local: SocketAddr,
+    host: String,
     port: u16,
 }
 
+impl FromStr for ForwardSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected [BIND_ADDRESS:]PORT:HOST:HOSTPORT, got {}", s);
+        let (rest, port) = s.rsplit_once(':').ok_or_else(error)?;
+        // IPv6 addresses go in brackets
+        let (local, host) = match rest.strip_suffix(']') {
+            Some(rest) => {
+                let (local, host) = rest.rsplit_once('[').ok_or_else(error)?;
+                (local.strip_suffix(':').ok_or_else(error)?, host)
+            }
+            None => rest.rsplit_once(':').ok_or_else(error)?,
+        };
+        let (bind, local_port) = match local.rsplit_once(':') {
+            Some((bind, port)) => (
+                bind.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .map_err(|_| error())?,
+                port,
+            ),
+            None => (IpAddr::from(Ipv4Addr::LOCALHOST), local),
+        };
+        Ok(ForwardSpec {
+            local: SocketAddr::new(bind, local_port.parse().map_err(|_| error())?),
+            host: host.to_string(),
+            port: port.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+// DNS over TLS server, port 853 unless given
+#[derive(Debug, Clone)]
+struct DotServer {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for DotServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected HOST[:PORT], got {}", s);
+        // IPv6 addresses go in brackets
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(error)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(error());
+        }
+        Ok(DotServer {
+            host: host.to_string(),
+            port: match port {
+                Some(port) => port.parse().map_err(|_| error())?,
+                None => tls::DOT_PORT,
+            },
+        })
+    }
+}
+
+// First nameserver configured in resolv.conf
+fn system_resolver() -> Option<IpAddr> {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+}
+
 fn main() {
-    let args = Args::parse();
-    let mut stdin_buffer = Vec::new();
-    let data: &[u8] = match &args.data {
-        Some(str) => str.as_bytes(),
-        None => {
-            let mut stdin = io::stdin();
-            stdin.read_to_end(&mut stdin_buffer).expect("io error");
-            stdin_buffer.as_slice()
+    match Args::parse().command {
+        Command::Send {
+            data,
+            listen,
+            tunnel,
+        } => {
+            let data = match data {
+                Some(str) => str.into_bytes(),
+                None => {
+                    let mut buffer = Vec::new();
+                    io::stdin().read_to_end(&mut buffer).expect("io error");
+                    buffer
+                }
+            };
+            send(&tunnel, &data, listen);
         }
+        Command::Forward { spec, tunnel } => {
+            accept(spec.local, move |socket| forward(&tunnel, socket, &spec))
+        }
+        Command::Socks {
+            bind,
+            username,
+            password_file: password,
+            tunnel,
+        } => {
+            let credentials = username
+                .zip(password)
+                .map(|(username, password)| Credentials { username, password });
+            accept(bind, move |socket| {
+                proxy(&tunnel, socket, credentials.as_ref())
+            })
+        }
+        Command::Keygen {
+            file,
+            format,
+            passphrase,
+        } => {
+            if let Err(e) = keygen(&file, format, passphrase) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn keygen(file: &Path, format: KeyFormat, passphrase: bool) -> Result<(), KeyError> {
+    let contents = if passphrase {
+        KeyDerivation::generate().encode()
+    } else {
+        Key::generate().encode(format)
     };
-    let dest_addr = SocketAddr::new(
-        IpAddr::from_str(&args.dest).expect("Invalid IP address provided"),
-        args.port.into(),
-    );
-
-    let mut payload = Payload::new(data.to_vec(), Path::new(&args.key), None);
-    let mut packet = Packet::new(false);
-    payload.encrypt().expect("");
-    packet
-        .embed_data(payload.as_slice(), None)
-        .expect("embed error");
-
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("");
-    socket
-        .send_to(
-            packet
-                .serialize(1)
-                .expect("serialization error")
-                .as_raw_slice(),
-            &dest_addr,
-        )
-        .expect("send error");
-
-    let mut buf = [0u8; 512];
-    socket.recv_from(&mut buf).expect("recv error");
-    let mut recv_packet = Packet::new(true);
-    recv_packet
-        .deserialize(buf.iter())
-        .expect("deserialize error");
-    let recv_data = recv_packet.extract_data();
-    let mut recv_payload = Payload::new(recv_data.to_vec(), Path::new(&args.key), None);
-    recv_payload.decrypt().expect("decrypt error");
-
-    println!("{}", recv_payload);
+    keys::write_key_file(file, &contents)
+}
+
+// Handle every connection to `addr` in a thread of its own
+fn accept<F>(addr: SocketAddr, handle: F)
+where
+    F: Fn(TcpStream) -> Result<(), Box<dyn error::Error>> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).expect("Error open port");
+    let handle = Arc::new(handle);
+    for connection in listener.incoming() {
+        let socket = match connection {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("accept error: {}", e);
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(socket) {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+fn new_client(args: &TunnelArgs) -> Client {
+    let mut client = Client::new(args.domain.clone(), args.key.clone());
+    if let Some(record_type) = args.record_type {
+        client.set_record_type(record_type);
+    }
+    client.set_codec(args.codec);
+    client.set_udp_len(args.udp_size);
+    // Responses over streams can be as long as the data needs
+    client.set_tcp(args.tcp || args.doh.is_some() || args.dot.is_some());
+    client
+}
+
+fn parse_key(path: &str) -> Result<Key, String> {
+    let passphrase = env::var(keys::PASSPHRASE_VAR).ok();
+    Key::load(Path::new(path), passphrase.as_deref()).map_err(|e| e.to_string())
+}
+
+fn parse_password(path: &str) -> Result<String, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(text.lines().next().unwrap_or_default().to_string())
+}
+
+fn parse_codec(name: &str) -> Result<&'static dyn LabelCodec, String> {
+    codec::by_name(name).ok_or_else(|| format!("unknown codec {}", name))
+}
+
+fn send(args: &TunnelArgs, data: &[u8], listen: bool) {
+    let mut tunnel = Tunnel::new(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let mut client = new_client(args);
+    client.stream().write_message(&Request::Chat.serialize());
+    if !data.is_empty() {
+        client.stream().write_message(data);
+    }
+    loop {
+        while let Some(message) = client.stream().read_message() {
+            println!("{}", String::from_utf8_lossy(&message));
+        }
+        // Done once the server acknowledged everything
+        if !listen && client.session().is_some() && client.stream().is_idle() {
+            break;
+        }
+        thread::sleep(client.poll_delay());
+        if let Err(e) = tunnel.step(&mut client) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Relay one connection over a session of its own
+fn forward(
+    args: &TunnelArgs,
+    socket: TcpStream,
+    spec: &ForwardSpec,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut tunnel = Tunnel::new(args)?;
+    let mut client = new_client(args);
+    client
+        .stream()
+        .write_message(&Request::Connect(spec.host.clone(), spec.port).serialize());
+    run_relay(&mut tunnel, &mut client, Relay::accept(socket)?)
+}
+
+// Relay a SOCKS connection to the host it asks for
+fn proxy(
+    args: &TunnelArgs,
+    mut socket: TcpStream,
+    credentials: Option<&Credentials>,
+) -> Result<(), Box<dyn error::Error>> {
+    // Clients which connect and then say nothing are dropped
+    socket.set_read_timeout(Some(SOCKS_HANDSHAKE_TIMEOUT))?;
+    let (host, port) = socks::handshake(&mut socket, credentials)?;
+    socket.set_read_timeout(None)?;
+    let mut tunnel = Tunnel::new(args)?;
+    let mut client = new_client(args);
+    client
+        .stream()
+        .write_message(&Request::Connect(host, port).serialize());
+    // The SOCKS client waits to hear whether the server could connect
+    let reply = loop {
+        if let Some(reply) = relay::read_reply(client.stream()) {
+            break reply;
+        }
+        thread::sleep(client.poll_delay());
+        tunnel.step(&mut client)?;
+    };
+    socks::reply(&mut socket, reply.is_ok())?;
+    if let Err(e) = reply {
+        // The server ended the session already
+        client.stream().close();
+        while !client.stream().is_closed() {
+            thread::sleep(client.poll_delay());
+            tunnel.step(&mut client)?;
+        }
+        return Err(e.into());
+    }
+    run_relay(&mut tunnel, &mut client, Relay::established(socket)?)
+}
+
+// Carry the connection's data until both ends are done with it
+fn run_relay(
+    tunnel: &mut Tunnel,
+    client: &mut Client,
+    mut relay: Relay,
+) -> Result<(), Box<dyn error::Error>> {
+    loop {
+        relay.pump(client.stream());
+        if let Some(e) = relay.error() {
+            return Err(e.to_string().into());
+        }
+        if client.stream().is_closed() {
+            return Ok(());
+        }
+        tunnel.step(client)?;
+        relay.pump(client.stream());
+        // Data from the connection cuts the wait short
+        relay.wait(client.poll_delay());
+    }
+}
+
+// How queries reach the server
+enum Transport {
+    // Datagrams, and TCP for queries whose responses come back truncated, or
+    // for all of them
+    Dns {
+        socket: UdpSocket,
+        dest_addr: SocketAddr,
+        tcp_only: bool,
+    },
+    Https {
+        url: DohUrl,
+        method: Method,
+        config: Arc<ClientConfig>,
+    },
+    Tls {
+        server: DotServer,
+        config: Arc<ClientConfig>,
+    },
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+// Connection queries go over, TLS or not, and the socket under it to set
+// timeouts on
+struct Connection {
+    stream: BufReader<Box<dyn ReadWrite>>,
+    socket: TcpStream,
+}
+
+impl Transport {
+    fn connect(&self, deadline: Instant) -> Result<Connection, Box<dyn error::Error>> {
+        let addr = match self {
+            Transport::Dns { dest_addr, .. } => *dest_addr,
+            Transport::Https { url, .. } => resolve(&url.host, url.port)?,
+            Transport::Tls { server, .. } => resolve(&server.host, server.port)?,
+        };
+        let socket = TcpStream::connect_timeout(&addr, time_left(deadline)?)?;
+        socket.set_nodelay(true)?;
+        let stream: Box<dyn ReadWrite> = match self {
+            Transport::Dns { .. } => Box::new(socket.try_clone()?),
+            Transport::Https { url, config, .. } => {
+                let tls = ClientConnection::new(config.clone(), tls::server_name(&url.host)?)?;
+                Box::new(StreamOwned::new(tls, socket.try_clone()?))
+            }
+            Transport::Tls { server, config } => {
+                let tls = ClientConnection::new(config.clone(), tls::server_name(&server.host)?)?;
+                Box::new(StreamOwned::new(tls, socket.try_clone()?))
+            }
+        };
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            socket,
+        })
+    }
+
+    fn write_query(&self, connection: &mut Connection, query: &[u8]) -> io::Result<()> {
+        let stream = connection.stream.get_mut();
+        match self {
+            Transport::Dns { .. } | Transport::Tls { .. } => tcp::write_message(stream, query),
+            Transport::Https { url, method, .. } => doh::write_query(stream, url, *method, query),
+        }
+    }
+
+    fn read_response(&self, connection: &mut Connection) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let response = match self {
+            Transport::Dns { .. } | Transport::Tls { .. } => {
+                tcp::read_message(&mut connection.stream)
+            }
+            Transport::Https { .. } => match doh::read_response(&mut connection.stream) {
+                Ok(response) => Ok(Some(response)),
+                Err(DohError::Io(e)) => Err(e),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        match response {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err("Connection closed by server".into()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err("Timed out waiting for response".into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Where the client's queries go out. Connections are opened when first needed
+// and kept for the queries after.
+struct Tunnel {
+    transport: Transport,
+    connection: Option<Connection>,
+    // Exchanges failed in a row
+    failures: u32,
+}
+
+impl Tunnel {
+    fn new(args: &TunnelArgs) -> Result<Self, Box<dyn error::Error>> {
+        let ca_cert = args.ca_cert.as_deref();
+        let transport = match (&args.doh, &args.dot) {
+            (Some(url), _) => Transport::Https {
+                url: url.clone(),
+                method: args.doh_method,
+                config: tls::client_config(ca_cert, &args.pin, doh::ALPN)?,
+            },
+            (_, Some(server)) => Transport::Tls {
+                server: server.clone(),
+                config: tls::client_config(ca_cert, &args.pin, tls::DOT_ALPN)?,
+            },
+            (None, None) => {
+                let dest_addr = SocketAddr::new(
+                    args.dest
+                        .or_else(system_resolver)
+                        .ok_or("No resolver configured, provide server or resolver address")?,
+                    args.port,
+                );
+                let bind_addr: IpAddr = match dest_addr {
+                    SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                };
+                Transport::Dns {
+                    socket: UdpSocket::bind((bind_addr, 0))?,
+                    dest_addr,
+                    tcp_only: args.tcp,
+                }
+            }
+        };
+        Ok(Tunnel {
+            transport,
+            connection: None,
+            failures: 0,
+        })
+    }
+
+    // Send the client's next query and wait for the response. Errors mean
+    // the server is out of reach.
+    fn step(&mut self, client: &mut Client) -> Result<(), Box<dyn error::Error>> {
+        let query = client.next_query(Instant::now())?;
+        let result = match &self.transport {
+            Transport::Dns {
+                socket,
+                dest_addr,
+                tcp_only: false,
+            } => match exchange(socket, *dest_addr, client, &query) {
+                // Too long for UDP, the same query gets the full response
+                // over TCP
+                Err(e) if e.downcast_ref() == Some(&ClientError::Truncated) => {
+                    exchange_stream(&self.transport, &mut self.connection, client, &query)
+                }
+                result => result,
+            },
+            _ => exchange_stream(&self.transport, &mut self.connection, client, &query),
+        };
+        match result {
+            Ok(()) => self.failures = 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                self.failures += 1;
+                if self.failures >= MAX_FAILURES {
+                    return Err(format!("Giving up after {} failed queries", self.failures).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Send the query in a datagram and wait for the response
+fn exchange(
+    socket: &UdpSocket,
+    dest_addr: SocketAddr,
+    client: &mut Client,
+    query: &[u8],
+) -> Result<(), Box<dyn error::Error>> {
+    socket.send_to(query, dest_addr)?;
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+    loop {
+        socket.set_read_timeout(Some(time_left(deadline)?))?;
+        let (number_of_bytes, src_addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err("Timed out waiting for response".into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Ignore anything that isn't the answer to our query
+        if src_addr == dest_addr && client.handle_response(&buf[..number_of_bytes])? {
+            return Ok(());
+        }
+    }
+}
+
+// Send the query over the connection, opening one if needed, and wait for
+// the response. Connections which fail or time out are dropped, the next
+// query opens a new one.
+fn exchange_stream(
+    transport: &Transport,
+    connection: &mut Option<Connection>,
+    client: &mut Client,
+    query: &[u8],
+) -> Result<(), Box<dyn error::Error>> {
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut open = match connection.take() {
+        Some(open) => open,
+        None => transport.connect(deadline)?,
+    };
+    open.socket.set_read_timeout(Some(time_left(deadline)?))?;
+    transport.write_query(&mut open, query)?;
+    loop {
+        open.socket.set_read_timeout(Some(time_left(deadline)?))?;
+        // Responses to earlier queries which timed out may still arrive
+        if client.handle_response(&transport.read_response(&mut open)?)? {
+            *connection = Some(open);
+            return Ok(());
+        }
+    }
+}
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, Box<dyn error::Error>> {
+    Ok((host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("No address for {}", host))?)
+}
+
+fn time_left(deadline: Instant) -> Result<Duration, Box<dyn error::Error>> {
+    Ok(deadline
+        .checked_duration_since(Instant::now())
+        .filter(|t| !t.is_zero())
+        .ok_or("Timed out waiting for response")?)
 }
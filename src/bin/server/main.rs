@@ -1,17 +1,66 @@
-use std::net::{Ipv4Addr, UdpSocket};
-use std::path::Path;
+use std::collections::{hash_map::Entry, HashMap};
+use std::env;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{ArgGroup, Parser};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
-use dns_camo::dns_packet::Packet;
-use dns_camo::payload::Payload;
+use dns_camo::dns_packet::{DnsName, MAX_EDNS_UDP_LEN};
+use dns_camo::doh::{self, DohError};
+use dns_camo::keys::{self, Key, Keyring};
+use dns_camo::relay::{Relay, Request};
+use dns_camo::server::Server;
+use dns_camo::{tcp, tls};
+
+// TCP connections with no query for this long are closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group = ArgGroup::new("keys").args(["key", "keyring"]).required(true))]
 struct Args {
-    /// Path to key file
+    /// Path to key file all clients share, as written by the client's keygen.
+    /// Keys derived from a passphrase take it from DNS_CAMO_PASSPHRASE
+    #[arg(short, long, value_parser = parse_key)]
+    key: Option<Key>,
+
+    /// Path to keyring file, with a line for each client giving its identity
+    /// and its key in base64
+    #[arg(long, value_name = "FILE", value_parser = parse_keyring)]
+    keyring: Option<Keyring>,
+
+    /// Tunnel domain this server is delegated, e.g. t.example.org
     #[arg(short, long)]
-    key: String,
+    domain: DnsName,
+
+    /// Host name of this server in NS and SOA records [default: ns.<DOMAIN>]
+    #[arg(long)]
+    nameserver: Option<DnsName>,
+
+    /// Also answer DNS over HTTPS queries to /dns-query on this port
+    #[arg(long, value_name = "PORT", requires_all = ["tls_cert", "tls_key"])]
+    doh_port: Option<u16>,
+
+    /// Also answer DNS over TLS queries on this port, usually 853
+    #[arg(long, value_name = "PORT", requires_all = ["tls_cert", "tls_key"])]
+    dot_port: Option<u16>,
+
+    /// PEM file with the certificate chain presented to clients
+    #[arg(long, value_name = "PEM")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate
+    #[arg(long, value_name = "PEM")]
+    tls_key: Option<PathBuf>,
 
     /// Server listening port
     port: u16,
@@ -19,36 +68,349 @@ struct Args {
 
 fn main() {
     let args = Args::parse();
-    let mut buf = [0u8; 512];
+    let nameserver = args.nameserver.unwrap_or_else(|| {
+        let mut name = args.domain.clone();
+        name.0.insert(0, String::from("ns"));
+        name
+    });
+    // Clap makes sure there's one or the other
+    let keys = args
+        .keyring
+        .or_else(|| args.key.map(Keyring::single))
+        .expect("key or keyring");
+    let mut server = Server::new(args.domain, nameserver, keys);
+
+    // Lines typed on stdin are sent to every client
+    let (line_tx, line_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Queries from all transports are answered in the order they arrive
+    let (query_tx, query_rx) = mpsc::channel();
     let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port)).expect("Error open port");
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, args.port)).expect("Error open port");
+    let udp_socket = socket.try_clone().expect("Error open port");
+    let udp_tx = query_tx.clone();
+    thread::spawn(move || receive_udp(udp_socket, udp_tx));
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        // For clients to pin instead of trusting whoever signed it
+        match tls::Pin::of_file(cert) {
+            Ok(pin) => println!("TLS public key pin: {}", pin),
+            Err(e) => exit_with(e),
+        }
+        if let Some(port) = args.doh_port {
+            let config = tls::server_config(cert, key, doh::ALPN).unwrap_or_else(|e| exit_with(e));
+            let listener =
+                TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).expect("Error open port");
+            let https_tx = query_tx.clone();
+            thread::spawn(move || {
+                accept(listener, move |stream| {
+                    receive_https(stream, config.clone(), https_tx.clone())
+                })
+            });
+        }
+        if let Some(port) = args.dot_port {
+            let config =
+                tls::server_config(cert, key, tls::DOT_ALPN).unwrap_or_else(|e| exit_with(e));
+            let listener =
+                TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).expect("Error open port");
+            let tls_tx = query_tx.clone();
+            thread::spawn(move || {
+                accept(listener, move |stream| {
+                    receive_tls(stream, config.clone(), tls_tx.clone())
+                })
+            });
+        }
+    }
+    thread::spawn(move || {
+        accept(listener, move |stream| {
+            receive_tcp(stream, query_tx.clone())
+        })
+    });
+
+    let mut apps = HashMap::new();
+    for (query, src) in query_rx {
+        // Queue what arrived for the clients since the last query
+        let lines: Vec<String> = line_rx.try_iter().collect();
+        for (&id, app) in apps.iter_mut() {
+            let Some(stream) = server.stream(id) else {
+                continue;
+            };
+            match app {
+                App::Chat => {
+                    for line in &lines {
+                        stream.write_message(line.as_bytes());
+                    }
+                }
+                App::Relay(relay) => relay.pump(stream),
+            }
+        }
+        // A malformed query only costs us that query
+        let replays = server.replays();
+        let reply = match &src {
+            Source::Udp(_) => server.handle(&query),
+            Source::Tcp(..) | Source::Https(..) | Source::Tls(..) => server.handle_tcp(&query),
+        };
+        if server.replays() > replays {
+            eprintln!("Dropped replayed query from {}", src);
+        }
+        match reply {
+            Ok(reply) => src.reply(&socket, reply),
+            Err(e) => eprintln!("Dropping query from {}: {}", src, e),
+        }
+        serve(&mut server, &mut apps);
+    }
+}
+
+// Where a query came from, and how to send the response back
+enum Source {
+    Udp(SocketAddr),
+    // Responses go to the thread writing to the connection
+    Tcp(SocketAddr, Sender<Vec<u8>>),
+    Https(SocketAddr, Sender<Vec<u8>>),
+    Tls(SocketAddr, Sender<Vec<u8>>),
+}
+
+impl Source {
+    fn reply(&self, socket: &UdpSocket, reply: Vec<u8>) {
+        match self {
+            Source::Udp(addr) => {
+                if let Err(e) = socket.send_to(&reply, addr) {
+                    eprintln!("send error: {}", e);
+                }
+            }
+            // The connection may have been closed in the meantime
+            Source::Tcp(_, writer) | Source::Https(_, writer) | Source::Tls(_, writer) => {
+                writer.send(reply).ok();
+            }
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Udp(addr) => write!(f, "{}", addr),
+            Source::Tcp(addr, _) => write!(f, "{} (TCP)", addr),
+            Source::Https(addr, _) => write!(f, "{} (HTTPS)", addr),
+            Source::Tls(addr, _) => write!(f, "{} (TLS)", addr),
+        }
+    }
+}
+
+fn receive_udp(socket: UdpSocket, queries: Sender<(Vec<u8>, Source)>) {
+    let mut buf = [0u8; MAX_EDNS_UDP_LEN];
     loop {
-        let (number_of_bytes, src_addr) = socket.recv_from(&mut buf).expect("error listening");
-        let mut packet = Packet::new(false);
-        packet
-            .deserialize(buf.iter().take(number_of_bytes))
-            .expect("deserialize error");
-        let data = packet.extract_data();
-        let mut payload = Payload::new(data, Path::new("/tmp/key"), None);
-        payload.decrypt().expect("decrypt error");
-        println!("{}", payload);
-
-        let mut reply_packet = Packet::new(true);
-        let mut reply_data = Vec::new();
-        reply_data.push(payload.as_slice().to_vec().len().try_into().expect(""));
-        let mut reply_payload = Payload::new(reply_data, Path::new("/tmp/key"), None);
-        reply_payload.encrypt().expect("encrypt error");
-        reply_packet
-            .embed_data(reply_payload.as_slice(), Some(&packet))
-            .expect("embed error");
-
-        socket
-            .send_to(
-                reply_packet
-                    .serialize(1)
-                    .expect("serialize error")
-                    .as_raw_slice(),
-                src_addr
-            )
-            .expect("send error");
+        let (number_of_bytes, src_addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("error listening: {}", e);
+                continue;
+            }
+        };
+        if queries
+            .send((buf[..number_of_bytes].to_vec(), Source::Udp(src_addr)))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Handle every connection in a thread of its own
+fn accept<F>(listener: TcpListener, handle: F)
+where
+    F: Fn(TcpStream) -> Result<(), Box<dyn error::Error>> + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    for connection in listener.incoming() {
+        match connection {
+            Ok(stream) => {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream) {
+                        eprintln!("connection error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("accept error: {}", e),
+        }
+    }
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Read the queries on a connection until the client closes it or goes idle.
+// Clients may send more queries before the responses to earlier ones came,
+// and a thread of its own writes the responses as they come.
+fn receive_tcp(
+    mut stream: TcpStream,
+    queries: Sender<(Vec<u8>, Source)>,
+) -> Result<(), Box<dyn error::Error>> {
+    let addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let (reply_tx, reply_rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for reply in reply_rx {
+            if tcp::write_message(&mut writer, &reply).is_err() {
+                break;
+            }
+        }
+    });
+    loop {
+        let query = match tcp::read_message(&mut stream) {
+            Ok(Some(query)) => query,
+            Ok(None) => break,
+            Err(e) if timed_out(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if queries
+            .send((query, Source::Tcp(addr, reply_tx.clone())))
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Answer the HTTP requests on a connection one after the other, until the
+// client closes it or goes idle
+fn receive_https(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    queries: Sender<(Vec<u8>, Source)>,
+) -> Result<(), Box<dyn error::Error>> {
+    let addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let mut stream = BufReader::new(StreamOwned::new(ServerConnection::new(config)?, stream));
+    loop {
+        let query = match doh::read_query(&mut stream, doh::DEFAULT_PATH) {
+            Ok(Some(query)) => query,
+            Ok(None) => break,
+            Err(DohError::Status(status)) => {
+                doh::write_error(stream.get_mut(), status)?;
+                break;
+            }
+            Err(DohError::Io(e)) if timed_out(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        queries.send((query, Source::Https(addr, reply_tx)))?;
+        match reply_rx.recv() {
+            Ok(reply) => doh::write_response(stream.get_mut(), &reply)?,
+            // Dropped as malformed
+            Err(_) => {
+                doh::write_error(stream.get_mut(), 400)?;
+                break;
+            }
+        }
+    }
+    let stream = stream.get_mut();
+    stream.conn.send_close_notify();
+    stream.flush().ok();
+    Ok(())
+}
+
+// Answer the DNS messages on a TLS connection, one after the other, until the
+// client closes it or goes idle
+fn receive_tls(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    queries: Sender<(Vec<u8>, Source)>,
+) -> Result<(), Box<dyn error::Error>> {
+    let addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let mut stream = StreamOwned::new(ServerConnection::new(config)?, stream);
+    loop {
+        let query = match tcp::read_message(&mut stream) {
+            Ok(Some(query)) => query,
+            Ok(None) => break,
+            Err(e) if timed_out(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        queries.send((query, Source::Tls(addr, reply_tx)))?;
+        // Malformed queries go unanswered, as over UDP
+        if let Ok(reply) = reply_rx.recv() {
+            tcp::write_message(&mut stream, &reply)?;
+        }
+    }
+    stream.conn.send_close_notify();
+    stream.flush().ok();
+    Ok(())
+}
+
+fn exit_with(e: impl fmt::Display) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+fn parse_key(path: &str) -> Result<Key, String> {
+    let passphrase = env::var(keys::PASSPHRASE_VAR).ok();
+    Key::load(Path::new(path), passphrase.as_deref()).map_err(|e| e.to_string())
+}
+
+fn parse_keyring(path: &str) -> Result<Keyring, String> {
+    Keyring::from_file(Path::new(path)).map_err(|e| e.to_string())
+}
+
+// What a session is used for, known once its request arrived
+enum App {
+    Chat,
+    Relay(Relay),
+}
+
+// Handle what arrived on the sessions' streams
+fn serve(server: &mut Server, apps: &mut HashMap<u16, App>) {
+    let ids = server.session_ids();
+    // Sessions which expired take their apps with them
+    apps.retain(|id, _| ids.contains(id));
+    for id in ids {
+        let client = server.client(id).unwrap_or_default().to_string();
+        let Some(stream) = server.stream(id) else {
+            continue;
+        };
+        let app = match apps.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Some(request) = stream.read_message() else {
+                    continue;
+                };
+                match Request::deserialize(&request) {
+                    Ok(Request::Chat) => entry.insert(App::Chat),
+                    Ok(Request::Connect(host, port)) => {
+                        println!("{:04x}: {} connecting to {}:{}", id, client, host, port);
+                        entry.insert(App::Relay(Relay::connect(host, port)))
+                    }
+                    Err(e) => {
+                        eprintln!("{:04x}: {}", id, e);
+                        server.close(id);
+                        continue;
+                    }
+                }
+            }
+        };
+        match app {
+            App::Chat => {
+                while let Some(message) = stream.read_message() {
+                    println!("{:04x}: {}", id, String::from_utf8_lossy(&message));
+                }
+            }
+            App::Relay(relay) => relay.pump(stream),
+        }
+        // Both sides are done with the session
+        if stream.is_closed() {
+            apps.remove(&id);
+            server.close(id);
+        }
     }
 }
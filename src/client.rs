@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::codec::{self, LabelCodec};
+use crate::dns_packet::{
+    DnsName, Edns, Packet, RecordType, ResponseCode, MAX_EDNS_UDP_LEN, MAX_UDP_LEN, OPT_LEN,
+};
+use crate::handshake::{Handshake, SessionKeys, PUBLIC_LEN};
+use crate::keys::Key;
+use crate::payload::Payload;
+use crate::replay::{self, ReplayWindow};
+use crate::session::{self, Direction, ID_LEN, NONCE_LEN, OPEN_ID, PROBE_ID};
+use crate::stream::{Segment, Stream};
+use crate::tcp::MAX_TCP_LEN;
+
+// The server can only send data in responses, so the client keeps polling
+// for it. The interval doubles with every poll the server had nothing for.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Room kept in the response to a query full of data, for the OPT record, the
+// acknowledgement and some data to come back
+const RESPONSE_ROOM: usize = OPT_LEN + Payload::OVERHEAD + Segment::HEADER_LEN + 32;
+// Record types probed for at the start, those carrying the most data first.
+// A is the last resort when no probe makes it back.
+const PROBE_ORDER: [RecordType; 7] = [
+    RecordType::NULL,
+    RecordType::TXT,
+    RecordType::MX,
+    RecordType::SRV,
+    RecordType::CNAME,
+    RecordType::AAAA,
+    RecordType::A,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientError {
+    // The response didn't fit in a datagram, the query has to be sent again
+    // over TCP
+    Truncated,
+    // The response carried a message which already arrived
+    Replayed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Truncated => write!(f, "Response truncated"),
+            ClientError::Replayed => write!(f, "Replayed response dropped"),
+        }
+    }
+}
+
+impl error::Error for ClientError {}
+
+// Tunnel client, turning the stream into DNS queries and the responses back.
+// Only builds and parses datagrams, sending them and timing out is up to the
+// caller.
+pub struct Client {
+    domain: DnsName,
+    key: Key,
+    // Type of the records the server answers with, known once probing is done
+    record_type: RecordType,
+    // Types left to probe, the first one being probed, and whether a probe
+    // for it waits for its response
+    probes: VecDeque<RecordType>,
+    probing: bool,
+    // Encoding of the data in names
+    codec: &'static dyn LabelCodec,
+    // UDP payload size advertised with EDNS(0), which is left out at 512
+    udp_len: u16,
+    // Whether queries go over TCP, where responses aren't limited by it
+    over_tcp: bool,
+    // Assigned by the server in response to the request carrying the nonce
+    session: Option<u16>,
+    nonce: [u8; NONCE_LEN],
+    // Ephemeral key offered in the request, and the keys agreed on with it
+    handshake: Option<Handshake>,
+    session_keys: Option<SessionKeys>,
+    // Number of the next message we send in the session, the numbers of
+    // those received, and how many were dropped as replays
+    sent: u64,
+    received: ReplayWindow,
+    replays: u64,
+    stream: Stream,
+    // ID of the query waiting for its response
+    outstanding: Option<u16>,
+    // Whether the last query carried data, and how many exchanges in a row
+    // carried none either way
+    sent_data: bool,
+    quiet_polls: u32,
+}
+
+impl Client {
+    pub fn new(domain: DnsName, key: Key) -> Self {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        Client {
+            domain,
+            key,
+            record_type: RecordType::A,
+            probes: VecDeque::from(PROBE_ORDER),
+            probing: false,
+            codec: &codec::Base32,
+            udp_len: MAX_EDNS_UDP_LEN as u16,
+            over_tcp: false,
+            session: None,
+            nonce,
+            handshake: Some(Handshake::new()),
+            session_keys: None,
+            sent: 0,
+            received: ReplayWindow::new(),
+            replays: 0,
+            stream: Stream::new(),
+            outstanding: None,
+            sent_data: false,
+            quiet_polls: 0,
+        }
+    }
+
+    pub fn stream(&mut self) -> &mut Stream {
+        &mut self.stream
+    }
+
+    // Record type the server answers in for this session, instead of probing
+    // for the best one that makes it through. TXT and NULL answers carry the
+    // most data, the others help where those are filtered.
+    pub fn set_record_type(&mut self, record_type: RecordType) {
+        self.record_type = record_type;
+        self.probes.clear();
+        self.probing = false;
+    }
+
+    // Codec for the data in names. Base32 goes through any resolver, the
+    // others need resolvers to leave names alone to different degrees.
+    pub fn set_codec(&mut self, codec: &'static dyn LabelCodec) {
+        self.codec = codec;
+    }
+
+    // Largest response to take from the server, from 512 bytes without EDNS
+    // up to what the server sends at most
+    pub fn set_udp_len(&mut self, udp_len: u16) {
+        self.udp_len = udp_len.clamp(MAX_UDP_LEN as u16, MAX_EDNS_UDP_LEN as u16);
+    }
+
+    // Whether the caller sends all queries over TCP. Queries then carry as
+    // much data as fits in a name, whatever the response has to echo.
+    pub fn set_tcp(&mut self, over_tcp: bool) {
+        self.over_tcp = over_tcp;
+    }
+
+    pub fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    pub fn session(&self) -> Option<u16> {
+        self.session
+    }
+
+    // Number of responses dropped for carrying a message which already
+    // arrived
+    pub fn replays(&self) -> u64 {
+        self.replays
+    }
+
+    // How long to wait before the next query
+    pub fn poll_delay(&self) -> Duration {
+        if self.session.is_none() || self.stream.has_unsent() {
+            Duration::ZERO
+        } else if !self.stream.is_idle() {
+            // Waiting for acknowledgements, or to retransmit
+            MIN_POLL_INTERVAL
+        } else {
+            (MIN_POLL_INTERVAL * 2u32.pow(self.quiet_polls.min(8))).min(MAX_POLL_INTERVAL)
+        }
+    }
+
+    // Build the next query: probes for a record type, then a request for a
+    // session until we have one, then new or retransmitted data or just an
+    // acknowledgement. Responses to earlier queries are ignored from now on.
+    pub fn next_query(&mut self, now: Instant) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        // The last probe went unanswered
+        if std::mem::take(&mut self.probing) {
+            self.reject_probe();
+        }
+        let (id, plaintext) = match self.session {
+            _ if !self.probes.is_empty() => (PROBE_ID, self.nonce.to_vec()),
+            None => {
+                let handshake = self.handshake.as_ref().ok_or("session request failed")?;
+                (OPEN_ID, [&self.nonce[..], &handshake.public()].concat())
+            }
+            Some(id) => {
+                let max_len = if self.over_tcp {
+                    MAX_TCP_LEN
+                } else {
+                    self.udp_len as usize
+                } - RESPONSE_ROOM;
+                let capacity =
+                    Packet::query_capacity(&self.domain, self.record_type, self.codec, max_len)
+                        .saturating_sub(ID_LEN + Payload::OVERHEAD + Segment::HEADER_LEN);
+                let segment = self.stream.next_segment(capacity, now);
+                self.sent_data = !segment.data.is_empty();
+                (id, segment.serialize())
+            }
+        };
+        let (key, nonce) = match &self.session_keys {
+            Some(keys) if id != OPEN_ID && id != PROBE_ID => {
+                let counter = self.sent;
+                self.sent += 1;
+                (&keys.upstream, Some(replay::nonce(counter)))
+            }
+            _ => (&self.key, None),
+        };
+        let mut payload = Payload::new(plaintext, key, nonce);
+        payload.set_associated_data(&session::associated_data(id, Direction::Upstream));
+        payload.encrypt()?;
+
+        let mut query = Packet::new(false);
+        // Needed when talking to a recursive resolver, harmless for the server
+        query.set_recursion_desired(true);
+        if self.udp_len as usize > MAX_UDP_LEN {
+            query.set_edns(Some(Edns::new(self.udp_len)));
+        }
+        query.embed_data(
+            &session::prefix_id(id, payload.as_slice()),
+            None,
+            &self.domain,
+            self.codec,
+        )?;
+        match self.probes.front() {
+            Some(&record_type) => {
+                query.set_query_type(record_type);
+                self.probing = true;
+            }
+            None => query.set_query_type(self.record_type),
+        }
+        let query_id = OsRng.next_u32() as u16;
+        self.outstanding = Some(query_id);
+        Ok(query.serialize(query_id)?.into_vec())
+    }
+
+    // Process a datagram received after the last query. Returns whether it
+    // was the response to that query, errors mean the response was useless and
+    // the exchange counts as lost. Truncated responses leave the query waiting
+    // for the full response, which the caller gets by sending the query again
+    // over TCP.
+    pub fn handle_response(&mut self, response: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+        let mut packet = Packet::new(false);
+        if packet.deserialize(response).is_err()
+            || !packet.is_response()
+            || Some(packet.id()) != self.outstanding
+        {
+            return Ok(false);
+        }
+        if packet.is_truncated() {
+            return Err(ClientError::Truncated.into());
+        }
+        self.outstanding = None;
+        // Types whose answers don't make it back intact are simply passed over
+        if std::mem::take(&mut self.probing) {
+            if self.probe_passed(&packet) {
+                self.record_type = self.probes[0];
+                self.probes.clear();
+            } else {
+                self.reject_probe();
+            }
+            return Ok(true);
+        }
+        match packet.rcode() {
+            ResponseCode::NoError => (),
+            ResponseCode::NameError if self.session.is_some() => {
+                return Err("session closed by server".into())
+            }
+            rcode => return Err(format!("query failed: {:?}", rcode).into()),
+        }
+
+        let data = packet.extract_data(&self.domain)?;
+        // The server had nothing to say, not even an acknowledgement
+        if data.is_empty() {
+            return Ok(true);
+        }
+        let key = self
+            .session_keys
+            .as_ref()
+            .map_or(&self.key, |keys| &keys.downstream);
+        let mut payload = Payload::new(data, key, None);
+        let id = self.session.unwrap_or(OPEN_ID);
+        payload.set_associated_data(&session::associated_data(id, Direction::Downstream));
+        payload.decrypt()?;
+        match self.session {
+            None => {
+                let (nonce, rest) = payload
+                    .as_slice()
+                    .split_first_chunk::<NONCE_LEN>()
+                    .ok_or("bad session response")?;
+                if *nonce != self.nonce {
+                    return Err("response for another session request".into());
+                }
+                let (id, public) = session::split_id(rest).ok_or("bad session response")?;
+                let public: &[u8; PUBLIC_LEN] =
+                    public.try_into().map_err(|_| "bad session response")?;
+                let handshake = self.handshake.take().ok_or("bad session response")?;
+                let keys = handshake
+                    .finish(public, &self.key, true)
+                    .ok_or("bad session key")?;
+                self.session = Some(id);
+                self.session_keys = Some(keys);
+            }
+            Some(_) => {
+                let counter = replay::counter(payload.nonce()).ok_or("bad message number")?;
+                if !self.received.accept(counter) {
+                    self.replays += 1;
+                    return Err(ClientError::Replayed.into());
+                }
+                let segment = Segment::deserialize(payload.as_slice())?;
+                if self.sent_data || !segment.data.is_empty() {
+                    self.quiet_polls = 0;
+                } else {
+                    self.quiet_polls = self.quiet_polls.saturating_add(1);
+                }
+                self.stream.receive(segment)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Whether the probe response carries exactly the data the server put in.
+    // How much that is depends on what the resolver on the way takes.
+    fn probe_passed(&self, packet: &Packet) -> bool {
+        if packet.rcode() != ResponseCode::NoError {
+            return false;
+        }
+        let Ok(data) = packet.extract_data(&self.domain) else {
+            return false;
+        };
+        let mut payload = Payload::new(data, &self.key, None);
+        payload.set_associated_data(&session::associated_data(PROBE_ID, Direction::Downstream));
+        if payload.decrypt().is_err() {
+            return false;
+        }
+        match payload.as_slice().split_first_chunk::<NONCE_LEN>() {
+            Some((nonce, pattern)) => {
+                *nonce == self.nonce
+                    && !pattern.is_empty()
+                    && *pattern == session::probe_pattern(pattern.len())
+            }
+            None => false,
+        }
+    }
+
+    // Move on to the next type to probe, settling for the last one if none
+    // made it through
+    fn reject_probe(&mut self) {
+        if let Some(record_type) = self.probes.pop_front() {
+            if self.probes.is_empty() {
+                self.record_type = record_type;
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+
+use data_encoding::{BASE32_DNSSEC, BASE64URL_NOPAD, HEXLOWER_PERMISSIVE};
+
+// Encodings of tunnel data in the labels of DNS names. Names carrying data
+// start with the tag of their codec, so that the other side can tell how to
+// decode them. Tags are compared ignoring case, which resolvers may change.
+//
+// Hex, base32 and base36 survive resolvers randomizing the case of names.
+// Base64 needs a path which keeps the case, and raw labels one which passes
+// any byte through, but they carry a lot more data in the same name.
+pub trait LabelCodec: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn tag(&self) -> char;
+    // Whether names differing only in letter case carry different data
+    fn case_sensitive(&self) -> bool;
+    // Characters are bytes on the wire, only raw labels use those above 0x7F
+    fn encode(&self, data: &[u8]) -> String;
+    fn decode(&self, text: &str) -> Option<Vec<u8>>;
+    // Characters taken by `len` bytes of data
+    fn encoded_len(&self, len: usize) -> usize;
+    // Bytes of data which fit in at most `chars` characters
+    fn decoded_len(&self, chars: usize) -> usize;
+}
+
+pub const CODECS: [&dyn LabelCodec; 5] = [&Hex, &Base32, &Base36, &Base64, &Raw];
+
+pub fn by_name(name: &str) -> Option<&'static dyn LabelCodec> {
+    CODECS
+        .into_iter()
+        .find(|codec| codec.name().eq_ignore_ascii_case(name))
+}
+
+pub fn by_tag(tag: char) -> Option<&'static dyn LabelCodec> {
+    CODECS
+        .into_iter()
+        .find(|codec| codec.tag().eq_ignore_ascii_case(&tag))
+}
+
+#[derive(Debug)]
+pub struct Hex;
+
+impl LabelCodec for Hex {
+    fn name(&self) -> &'static str {
+        "hex"
+    }
+    fn tag(&self) -> char {
+        'h'
+    }
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+    fn encode(&self, data: &[u8]) -> String {
+        HEXLOWER_PERMISSIVE.encode(data)
+    }
+    fn decode(&self, text: &str) -> Option<Vec<u8>> {
+        HEXLOWER_PERMISSIVE.decode(text.as_bytes()).ok()
+    }
+    fn encoded_len(&self, len: usize) -> usize {
+        len * 2
+    }
+    fn decoded_len(&self, chars: usize) -> usize {
+        chars / 2
+    }
+}
+
+#[derive(Debug)]
+pub struct Base32;
+
+impl LabelCodec for Base32 {
+    fn name(&self) -> &'static str {
+        "base32"
+    }
+    fn tag(&self) -> char {
+        'b'
+    }
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+    fn encode(&self, data: &[u8]) -> String {
+        BASE32_DNSSEC.encode(data)
+    }
+    fn decode(&self, text: &str) -> Option<Vec<u8>> {
+        BASE32_DNSSEC.decode(text.as_bytes()).ok()
+    }
+    fn encoded_len(&self, len: usize) -> usize {
+        (len * 8).div_ceil(5)
+    }
+    fn decoded_len(&self, chars: usize) -> usize {
+        chars * 5 / 8
+    }
+}
+
+// Digits and letters, the data taken as one big number. Slightly denser than
+// base32 on longer names.
+#[derive(Debug)]
+pub struct Base36;
+
+impl Base36 {
+    const DIGITS: &'static [u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    // A digit holds log2(36) = 5.1699... bits, taken as 517 / 100. Lengths
+    // come out exact up to 326 bytes and 505 digits, well past the longest
+    // name, as `check_base36_lengths` goes through.
+    const DIGIT_BITS: usize = 517;
+    const DIGIT_BITS_SCALE: usize = 100;
+}
+
+impl LabelCodec for Base36 {
+    fn name(&self) -> &'static str {
+        "base36"
+    }
+    fn tag(&self) -> char {
+        'k'
+    }
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+    fn encode(&self, data: &[u8]) -> String {
+        let mut number = data.to_vec();
+        let mut digits = vec![0u8; self.encoded_len(data.len())];
+        // Least significant digit first, dividing the number by 36 each time
+        for digit in digits.iter_mut().rev() {
+            let mut rem = 0u32;
+            for byte in &mut number {
+                let value = (rem << 8) | *byte as u32;
+                *byte = (value / 36) as u8;
+                rem = value % 36;
+            }
+            *digit = Self::DIGITS[rem as usize];
+        }
+        digits.into_iter().map(char::from).collect()
+    }
+    fn decode(&self, text: &str) -> Option<Vec<u8>> {
+        let chars = text.chars().count();
+        let len = self.decoded_len(chars);
+        // Only lengths `encode` produces are valid
+        if self.encoded_len(len) != chars {
+            return None;
+        }
+        let mut number = vec![0u8; len];
+        for ch in text.chars() {
+            let mut carry = ch.to_digit(36)?;
+            for byte in number.iter_mut().rev() {
+                let value = *byte as u32 * 36 + carry;
+                *byte = value as u8;
+                carry = value >> 8;
+            }
+            if carry != 0 {
+                return None;
+            }
+        }
+        Some(number)
+    }
+    fn encoded_len(&self, len: usize) -> usize {
+        (len * 8 * Self::DIGIT_BITS_SCALE).div_ceil(Self::DIGIT_BITS)
+    }
+    fn decoded_len(&self, chars: usize) -> usize {
+        chars * Self::DIGIT_BITS / (8 * Self::DIGIT_BITS_SCALE)
+    }
+}
+
+// URL-safe base64 without padding, letters in both cases
+#[derive(Debug)]
+pub struct Base64;
+
+impl LabelCodec for Base64 {
+    fn name(&self) -> &'static str {
+        "base64"
+    }
+    fn tag(&self) -> char {
+        's'
+    }
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+    fn encode(&self, data: &[u8]) -> String {
+        BASE64URL_NOPAD.encode(data)
+    }
+    fn decode(&self, text: &str) -> Option<Vec<u8>> {
+        BASE64URL_NOPAD.decode(text.as_bytes()).ok()
+    }
+    fn encoded_len(&self, len: usize) -> usize {
+        (len * 4).div_ceil(3)
+    }
+    fn decoded_len(&self, chars: usize) -> usize {
+        chars * 3 / 4
+    }
+}
+
+// Data bytes as they are, one per character
+#[derive(Debug)]
+pub struct Raw;
+
+impl LabelCodec for Raw {
+    fn name(&self) -> &'static str {
+        "raw"
+    }
+    fn tag(&self) -> char {
+        'r'
+    }
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+    fn encode(&self, data: &[u8]) -> String {
+        data.iter().map(|&byte| byte as char).collect()
+    }
+    fn decode(&self, text: &str) -> Option<Vec<u8>> {
+        text.chars().map(|ch| u8::try_from(ch).ok()).collect()
+    }
+    fn encoded_len(&self, len: usize) -> usize {
+        len
+    }
+    fn decoded_len(&self, chars: usize) -> usize {
+        chars
+    }
+}
+
+// Tests
+
+#[test]
+fn check_codecs() {
+    let data: Vec<u8> = (0..=255).rev().collect();
+    for codec in CODECS {
+        for len in 0..=255 {
+            let encoded = codec.encode(&data[..len]);
+            assert_eq!(
+                encoded.chars().count(),
+                codec.encoded_len(len),
+                "{:?}",
+                codec
+            );
+            assert_eq!(
+                codec.decoded_len(encoded.chars().count()),
+                len,
+                "{:?}",
+                codec
+            );
+            assert_eq!(codec.decode(&encoded).as_deref(), Some(&data[..len]));
+        }
+        assert_eq!(
+            by_tag(codec.tag().to_ascii_uppercase()).map(|c| c.name()),
+            Some(codec.name())
+        );
+        assert_eq!(by_name(codec.name()).map(|c| c.tag()), Some(codec.tag()));
+    }
+
+    // Case only matters to base64 and raw labels
+    assert_eq!(
+        CODECS.map(|codec| codec.case_sensitive()),
+        [false, false, false, true, true]
+    );
+    assert_eq!(Base32.decode("AC"), Some(vec![0x53]));
+    assert_eq!(Base36.decode("ZZ"), Base36.decode("zz"));
+    assert_ne!(Base64.decode("AbCd"), Base64.decode("abcd"));
+    // Lengths or values no data encodes to are refused
+    assert_eq!(Base36.decode("000"), None);
+    assert_eq!(Base36.decode("zz"), None);
+    assert_eq!(Hex.decode("abc"), None);
+    assert_eq!(Raw.decode("\u{100}"), None);
+    assert_eq!(by_tag('q').map(|c| c.name()), None);
+}
+
+#[test]
+fn check_base36_lengths() {
+    // Powers of 36 in base 256, least significant byte first. k digits hold
+    // n bytes when 256^n <= 36^k, which is one less than the bytes of 36^k.
+    let mut power = vec![1u8];
+    let mut fits = Vec::new();
+    for _ in 0..=505 {
+        fits.push(power.len() - 1);
+        let mut carry = 0u32;
+        for byte in &mut power {
+            let value = *byte as u32 * 36 + carry;
+            *byte = value as u8;
+            carry = value >> 8;
+        }
+        if carry != 0 {
+            power.push(carry as u8);
+        }
+    }
+    for (chars, &len) in fits.iter().enumerate() {
+        assert_eq!(Base36.decoded_len(chars), len, "{} digits", chars);
+    }
+    for len in 0..=326 {
+        let chars = fits.iter().position(|&fit| fit >= len).unwrap();
+        assert_eq!(Base36.encoded_len(len), chars, "{} bytes", len);
+    }
+    // The largest numbers take all the digits
+    let data = [0xff; 255];
+    for len in 0..=data.len() {
+        let encoded = Base36.encode(&data[..len]);
+        assert_eq!(Base36.decode(&encoded).as_deref(), Some(&data[..len]));
+    }
+}
@@ -1,71 +1,226 @@
-use crate::payload::Payload;
 use bitvec::prelude::*;
-use data_encoding::BASE32_DNSSEC;
-use std::borrow::BorrowMut;
-use std::collections::btree_map::Iter;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error;
 use std::fmt;
-use std::path::Path;
-use std::iter;
+use std::str::FromStr;
+
+use crate::codec::{self, LabelCodec};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum DnsParseError {
     // Max length and length of given data
     DataExceedMaxLen(usize, usize),
-    UndefinedRecordType(u16),
     StreamFormatError,
+
+    // Following errors carry the byte offset in the message where they occurred
+    TruncatedHeader(usize),
+    TruncatedName(usize),
+    TruncatedRecord(usize),
+    TruncatedRdata(usize),
+    BadRdata(usize),
+    BadOpt(usize),
+    BadLabel(usize),
+    NameTooLong(usize),
+    PointerLoop(usize),
+
+    // Label which doesn't carry validly encoded data
+    UndecodableLabel(String),
+    // Query name which isn't under the tunnel domain
+    NameOutsideZone(String),
+    // Record type which can't carry tunnel data
+    UnsupportedRecordType(u16),
+    // Record type name which isn't one we know
+    UnknownRecordType(String),
 }
 
 impl fmt::Display for DnsParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             DnsParseError::DataExceedMaxLen(max_len, len) => write!(
                 f,
                 "Data exceed max length of the field ({}/{})",
                 len, max_len
             ),
-            DnsParseError::UndefinedRecordType(num) => {
-                write!(f, "Undefined record type / record class: {}", num)
-            }
             DnsParseError::StreamFormatError => write!(f, "Wrong format in DNS packet"),
+            DnsParseError::TruncatedHeader(offset) => {
+                write!(f, "Header truncated at offset {}", offset)
+            }
+            DnsParseError::TruncatedName(offset) => {
+                write!(f, "Name truncated at offset {}", offset)
+            }
+            DnsParseError::TruncatedRecord(offset) => {
+                write!(f, "Record truncated at offset {}", offset)
+            }
+            DnsParseError::TruncatedRdata(offset) => {
+                write!(f, "Record data truncated at offset {}", offset)
+            }
+            DnsParseError::BadRdata(offset) => {
+                write!(f, "Malformed record data at offset {}", offset)
+            }
+            DnsParseError::BadOpt(offset) => {
+                write!(f, "Malformed OPT record at offset {}", offset)
+            }
+            DnsParseError::BadLabel(offset) => write!(f, "Bad label at offset {}", offset),
+            DnsParseError::NameTooLong(offset) => {
+                write!(f, "Name exceeds 255 bytes at offset {}", offset)
+            }
+            DnsParseError::PointerLoop(offset) => {
+                write!(f, "Compression pointer loop at offset {}", offset)
+            }
+            DnsParseError::UndecodableLabel(label) => {
+                write!(f, "Label \"{}\" can't be decoded", label)
+            }
+            DnsParseError::NameOutsideZone(name) => {
+                write!(f, "Name {} is outside of the tunnel domain", name)
+            }
+            DnsParseError::UnsupportedRecordType(num) => {
+                write!(f, "Record type {} can't carry tunnel data", num)
+            }
+            DnsParseError::UnknownRecordType(name) => {
+                write!(f, "Unknown record type {}", name)
+            }
         }
     }
 }
 
 impl error::Error for DnsParseError {}
 
+// Largest DNS message carried over plain UDP
+pub const MAX_UDP_LEN: usize = 512;
+// Largest UDP payload we take or send with EDNS(0), whatever the other side
+// advertises. It avoids fragmentation on most paths, and resolvers advertise
+// sizes of their own upstream, so larger responses could still be too large
+// for the client behind them.
+pub const MAX_EDNS_UDP_LEN: usize = 1232;
+
+// Pointers in compressed names are 14 bits wide, so only names starting in the
+// first 16KiB of a message can be referred to.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+const POINTER_MASK: u8 = 0b11000000;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+// Read `len` bytes at `pos`, reporting a shortage with `err` at the offset the
+// read started from.
+fn read_bytes<'a>(
+    msg: &'a [u8],
+    pos: &mut usize,
+    len: usize,
+    err: fn(usize) -> DnsParseError,
+) -> Result<&'a [u8], DnsParseError> {
+    let bytes = msg.get(*pos..*pos + len).ok_or(err(*pos))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn read_u8(
+    msg: &[u8],
+    pos: &mut usize,
+    err: fn(usize) -> DnsParseError,
+) -> Result<u8, DnsParseError> {
+    Ok(read_bytes(msg, pos, 1, err)?[0])
+}
+
+fn read_u16(
+    msg: &[u8],
+    pos: &mut usize,
+    err: fn(usize) -> DnsParseError,
+) -> Result<u16, DnsParseError> {
+    let bytes = read_bytes(msg, pos, 2, err)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(
+    msg: &[u8],
+    pos: &mut usize,
+    err: fn(usize) -> DnsParseError,
+) -> Result<u32, DnsParseError> {
+    let bytes = read_bytes(msg, pos, 4, err)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum RecordType {
     A,
+    NS,
+    CNAME,
+    SOA,
+    NULL,
+    MX,
+    TXT,
     AAAA,
+    SRV,
+    // Any other type, which we only pass along
+    Unknown(u16),
 }
 
 impl RecordType {
     fn value(self) -> u16 {
         match self {
             Self::A => 1,
+            Self::NS => 2,
+            Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::NULL => 10,
+            Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::SRV => 33,
+            Self::Unknown(value) => value,
         }
     }
+    // Whether answers of the type can carry tunnel data
+    pub fn carries_data(self) -> bool {
+        matches!(
+            self,
+            Self::A | Self::AAAA | Self::TXT | Self::NULL | Self::CNAME | Self::MX | Self::SRV
+        )
+    }
     pub fn serialize<T: BitStore>(self, target_bv: &mut BitVec<T, Msb0>) {
         target_bv.extend_from_bitslice(self.value().view_bits::<Msb0>());
     }
-    fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        let bytes: [u8; 2] = [*iter.next().unwrap(), *iter.next().unwrap()];
-        match u16::from_be_bytes(bytes) {
-            1 => {
-                *self = Self::A;
-                Ok(())
-            }
-            28 => {
-                *self = Self::AAAA;
-                Ok(())
-            }
-            n => Err(DnsParseError::UndefinedRecordType(n)),
+    fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
+        *self = match read_u16(msg, pos, DnsParseError::TruncatedRecord)? {
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            10 => Self::NULL,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            n => Self::Unknown(n),
+        };
+        Ok(())
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = DnsParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(Self::A),
+            "NS" => Ok(Self::NS),
+            "CNAME" => Ok(Self::CNAME),
+            "SOA" => Ok(Self::SOA),
+            "NULL" => Ok(Self::NULL),
+            "MX" => Ok(Self::MX),
+            "TXT" => Ok(Self::TXT),
+            "AAAA" => Ok(Self::AAAA),
+            "SRV" => Ok(Self::SRV),
+            _ => Err(DnsParseError::UnknownRecordType(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Generic name of other types (RFC 3597)
+            Self::Unknown(value) => write!(f, "TYPE{}", value),
+            known => write!(f, "{:?}", known),
         }
     }
 }
@@ -76,6 +231,9 @@ pub enum RecordClass {
 
     // Following Fields only valid in QCLASS field of question section.
     ALL,
+
+    // Any other class, e.g. CH
+    Unknown(u16),
 }
 
 impl RecordClass {
@@ -84,114 +242,196 @@ impl RecordClass {
             Self::IN => 1,
 
             Self::ALL => 255,
+            Self::Unknown(value) => value,
         }
     }
     fn serialize<T: BitStore>(self, target_bv: &mut BitVec<T, Msb0>) {
         target_bv.extend_from_bitslice(self.value().view_bits::<Msb0>());
     }
-    fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        let bytes: [u8; 2] = [*iter.next().unwrap(), *iter.next().unwrap()];
-        match u16::from_be_bytes(bytes) {
-            1 => {
-                *self = Self::IN;
-                Ok(())
-            }
-            255 => {
-                *self = Self::ALL;
-                Ok(())
-            }
-            n => Err(DnsParseError::UndefinedRecordType(n)),
-        }
+    fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
+        *self = match read_u16(msg, pos, DnsParseError::TruncatedRecord)? {
+            1 => Self::IN,
+            255 => Self::ALL,
+            n => Self::Unknown(n),
+        };
+        Ok(())
     }
 }
 
-impl TryFrom<u16> for RecordClass {
-    type Error = DnsParseError;
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        match value {
-            1 => Ok(RecordClass::IN),
-
-            255 => Ok(RecordClass::ALL),
+// Offsets of names already written to a message, keyed by the lowercased
+// labels of every suffix, so that later names can point back to them.
+type CompressionMap = HashMap<Vec<String>, u16>;
 
-            n => Err(DnsParseError::UndefinedRecordType(n)),
-        }
-    }
-}
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub enum DnsName {
-    Str(Vec<String>),
-    // TODO: offset should process in serialization phase, DELETE this member
-    Offset(u16),
-}
+pub struct DnsName(pub Vec<String>);
 
 impl DnsName {
-    fn serialize<T: BitStore>(&self, target_bv: &mut BitVec<T, Msb0>) {
-        match self {
-            DnsName::Str(labels) => {
-                for label in labels {
-                    let len: u8 = label.len().try_into().unwrap();
-                    // TODO: String length check
-                    target_bv.extend_from_bitslice(len.view_bits::<Msb0>());
-                    label
-                        .chars()
-                        .map(|ch| ch.try_into().unwrap())
-                        .for_each(|b: u8| target_bv.extend_from_bitslice(b.view_bits::<Msb0>()))
-                }
-                target_bv.extend_from_bitslice(0u8.view_bits::<Msb0>());
+    fn serialize<T: BitStore>(
+        &self,
+        target_bv: &mut BitVec<T, Msb0>,
+        names: &mut CompressionMap,
+    ) -> Result<(), DnsParseError> {
+        let labels = &self.0;
+        let wire_len = self.wire_len();
+        if wire_len > MAX_NAME_LEN {
+            return Err(DnsParseError::DataExceedMaxLen(MAX_NAME_LEN, wire_len));
+        }
+        for i in 0..labels.len() {
+            let suffix: Vec<String> = labels[i..].iter().map(|l| l.to_ascii_lowercase()).collect();
+            if let Some(&offset) = names.get(&suffix) {
+                target_bv.extend_from_bitslice((0xC000u16 | offset).view_bits::<Msb0>());
+                return Ok(());
             }
-            DnsName::Offset(offset) => {
-                // TODO: Offset in range
-                // TODO: Write test to check first two bit
-                target_bv.extend_from_bitslice((offset | 0xCu16).view_bits::<Msb0>());
+            let offset = target_bv.len() / 8;
+            if offset <= MAX_POINTER_OFFSET {
+                names.insert(suffix, offset as u16);
             }
-        };
+            let bytes = labels[i]
+                .chars()
+                .map(|ch| u8::try_from(ch).map_err(|_| DnsParseError::BadLabel(offset)))
+                .collect::<Result<Vec<u8>, _>>()?;
+            if bytes.is_empty() {
+                return Err(DnsParseError::BadLabel(offset));
+            }
+            if bytes.len() > MAX_LABEL_LEN {
+                return Err(DnsParseError::DataExceedMaxLen(MAX_LABEL_LEN, bytes.len()));
+            }
+            target_bv.extend_from_bitslice((bytes.len() as u8).view_bits::<Msb0>());
+            target_bv.extend_from_bitslice(bytes.view_bits::<Msb0>());
+        }
+        target_bv.extend_from_bitslice(0u8.view_bits::<Msb0>());
+        Ok(())
     }
-    fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
+    fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
+        // Position to continue reading from, once the first pointer has been followed
+        let mut resume = None;
+        let mut cursor = *pos;
+        // Start of the labels currently being read
+        let mut segment_start = cursor;
+        let mut wire_len = 1;
         loop {
-            let count: usize = match iter.next() {
-                Some(0) => {
-                    break;
-                }
-                Some(&val) => val as usize,
-                None => {
-                    return Err(DnsParseError::StreamFormatError);
+            let label_start = cursor;
+            let count = read_u8(msg, &mut cursor, DnsParseError::TruncatedName)?;
+            if count == 0 {
+                break;
+            }
+            if count & POINTER_MASK == POINTER_MASK {
+                let low = read_u8(msg, &mut cursor, DnsParseError::TruncatedName)?;
+                let target = (((count & !POINTER_MASK) as usize) << 8) | low as usize;
+                // Every pointer must jump strictly before the labels read so
+                // far, so that following them always terminates
+                if target >= segment_start {
+                    return Err(DnsParseError::PointerLoop(label_start));
                 }
-            };
-            let s = String::from_iter((&mut iter).take(count).map(|&ch| ch as char));
-            match self {
-                Self::Str(v) => v.push(s),
-                Self::Offset(_) => return Err(DnsParseError::StreamFormatError),
+                resume.get_or_insert(cursor);
+                cursor = target;
+                segment_start = target;
+                continue;
+            }
+            if count & POINTER_MASK != 0 {
+                // 0b01 and 0b10 prefixes are reserved
+                return Err(DnsParseError::BadLabel(label_start));
             }
+            wire_len += count as usize + 1;
+            if wire_len > MAX_NAME_LEN {
+                return Err(DnsParseError::NameTooLong(label_start));
+            }
+            let label = read_bytes(
+                msg,
+                &mut cursor,
+                count as usize,
+                DnsParseError::TruncatedName,
+            )?;
+            self.0.push(label.iter().map(|&ch| ch as char).collect());
         }
+        *pos = resume.unwrap_or(cursor);
         Ok(())
     }
 }
 
+impl DnsName {
+    // Length of the name written out in full
+    pub fn wire_len(&self) -> usize {
+        self.0.iter().map(|l| l.chars().count() + 1).sum::<usize>() + 1
+    }
+
+    // Labels in front of `suffix`, if this name is `suffix` or a subdomain of it.
+    // Comparison is case-insensitive, as for any DNS name.
+    pub fn strip_suffix(&self, suffix: &DnsName) -> Option<&[String]> {
+        let split = self.0.len().checked_sub(suffix.0.len())?;
+        let (prefix, tail) = self.0.split_at(split);
+        tail.iter()
+            .zip(&suffix.0)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            .then_some(prefix)
+    }
+}
+
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.", self.0.join("."))
+    }
+}
+
 impl TryFrom<&String> for DnsName {
     type Error = DnsParseError;
     fn try_from(value: &String) -> Result<Self, Self::Error> {
-        // TODO: Check length error
-        Ok(DnsName::Str(
-            value.split('.').map(|str| String::from(str)).collect(),
-        ))
+        value.parse()
     }
 }
 
 impl FromStr for DnsName {
     type Err = DnsParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(DnsName::Str(
-            s.split('.').map(|str| String::from(str)).collect(),
+        // Empty labels only come from a leading or trailing dot
+        Ok(DnsName(
+            s.split('.')
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect(),
         ))
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+    #[default]
+    NoError,
+    FormatError,
+    ServerFailure,
+    NameError,
+    NotImplemented,
+    Refused,
+    // Extended code, needs an OPT record to carry its upper bits
+    BadVersion,
+}
+
+impl ResponseCode {
+    fn value(self) -> u16 {
+        match self {
+            Self::NoError => 0,
+            Self::FormatError => 1,
+            Self::ServerFailure => 2,
+            Self::NameError => 3,
+            Self::NotImplemented => 4,
+            Self::Refused => 5,
+            Self::BadVersion => 16,
+        }
+    }
+    fn from_value(value: u16) -> Self {
+        match value {
+            0 => Self::NoError,
+            1 => Self::FormatError,
+            3 => Self::NameError,
+            4 => Self::NotImplemented,
+            5 => Self::Refused,
+            16 => Self::BadVersion,
+            // Treat anything we don't know about as a failure
+            _ => Self::ServerFailure,
+        }
+    }
+}
+
 #[derive(Default, Debug, PartialEq, Eq)]
 struct Header {
     id: u16,
@@ -204,6 +444,10 @@ struct Header {
 
 impl Header {
     const FLAG_RESPONSE: u16 = 0b10000000_00000000;
+    const FLAG_AUTHORITATIVE: u16 = 0b00000100_00000000;
+    const FLAG_TRUNCATED: u16 = 0b00000010_00000000;
+    const FLAG_RECURSION_DESIRED: u16 = 0b00000001_00000000;
+    const RCODE_MASK: u16 = 0b00000000_00001111;
     pub fn serialize<T: BitStore>(&self, target_bv: &mut BitVec<T, Msb0>) {
         target_bv.extend_from_bitslice(self.id.view_bits::<Msb0>());
         target_bv.extend_from_bitslice(self.flags.view_bits::<Msb0>());
@@ -213,10 +457,7 @@ impl Header {
         target_bv.extend_from_bitslice(self.additional_count.view_bits::<Msb0>());
     }
 
-    pub fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
+    pub fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
         let to_modify = [
             &mut self.id,
             &mut self.flags,
@@ -226,15 +467,7 @@ impl Header {
             &mut self.additional_count,
         ];
         for member in to_modify {
-            *member = u16::from_be_bytes(
-                (&mut iter)
-                    .take(2)
-                    .cloned()
-                    .collect::<Vec<u8>>()
-                    .as_slice()
-                    .try_into()
-                    .map_err(|_| DnsParseError::StreamFormatError)?,
-            );
+            *member = read_u16(msg, pos, DnsParseError::TruncatedHeader)?;
         }
         Ok(())
     }
@@ -250,24 +483,26 @@ struct Question {
 impl Question {
     fn new() -> Self {
         Question {
-            qname: DnsName::Str(vec![]),
+            qname: DnsName(vec![]),
             qtype: RecordType::A,
             qclass: RecordClass::ALL,
         }
     }
-    pub fn serialize<T: BitStore>(&self, target_bv: &mut BitVec<T, Msb0>) {
-        self.qname.serialize(target_bv);
+    fn serialize<T: BitStore>(
+        &self,
+        target_bv: &mut BitVec<T, Msb0>,
+        names: &mut CompressionMap,
+    ) -> Result<(), DnsParseError> {
+        self.qname.serialize(target_bv, names)?;
         self.qtype.serialize(target_bv);
         self.qclass.serialize(target_bv);
+        Ok(())
     }
 
-    pub fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        self.qname.deserialize(&mut iter)?;
-        self.qtype.deserialize(&mut iter)?;
-        self.qclass.deserialize(&mut iter)?;
+    fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
+        self.qname.deserialize(msg, pos)?;
+        self.qtype.deserialize(msg, pos)?;
+        self.qclass.deserialize(msg, pos)?;
         Ok(())
     }
 }
@@ -285,7 +520,7 @@ struct Record {
 impl Record {
     fn new() -> Self {
         Self {
-            rname: DnsName::Str(vec![]),
+            rname: DnsName(vec![]),
             rtype: RecordType::A,
             rclass: RecordClass::IN,
             ttl: 0,
@@ -294,54 +529,407 @@ impl Record {
         }
     }
 
-    pub fn serialize<T: BitStore + Default + Clone>(&self, target_bv: &mut BitVec<T, Msb0>) {
-        self.rname.serialize(target_bv);
+    fn serialize<T: BitStore + Default + Clone>(
+        &self,
+        target_bv: &mut BitVec<T, Msb0>,
+        names: &mut CompressionMap,
+    ) -> Result<(), DnsParseError> {
+        let data_bytes = self.data.len() / 8;
+        if data_bytes > self.data_length as usize {
+            return Err(DnsParseError::DataExceedMaxLen(
+                self.data_length as usize,
+                data_bytes,
+            ));
+        }
+        self.rname.serialize(target_bv, names)?;
         self.rtype.serialize(target_bv);
         self.rclass.serialize(target_bv);
         target_bv.extend_from_bitslice(self.ttl.view_bits::<Msb0>());
         target_bv.extend_from_bitslice(self.data_length.view_bits::<Msb0>());
         target_bv.extend_from_bitslice(&self.data);
-        // TODO: More elegant way of alignment
-        let gap: i64 = (self.data_length as usize * 8 - self.data.len()).try_into().unwrap();
-        if gap > 0 {
-            let v: Vec<T> = vec![Default::default(); (gap / 8) as usize];
-            target_bv.extend_from_raw_slice(v.as_slice());
-        } else if gap < 0 {
-            panic!("Wrong data length in record")
-        }
-    }
-
-    fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        self.rname.deserialize(&mut iter)?;
-        self.rtype.deserialize(&mut iter)?;
-        self.rclass.deserialize(&mut iter)?;
-        self.ttl = u32::from_be_bytes(
-            (&mut iter)
-                .take(4)
-                .cloned()
-                .collect::<Vec<u8>>()
-                .as_slice()
-                .try_into()
-                .map_err(|_| DnsParseError::StreamFormatError)?,
-        );
-        self.data_length = u16::from_be_bytes(
-            (&mut iter)
-                .take(2)
-                .cloned()
-                .collect::<Vec<u8>>()
-                .as_slice()
-                .try_into()
-                .map_err(|_| DnsParseError::StreamFormatError)?,
-        );
-        self.data = (&mut iter).take(self.data_length as usize).collect();
+        // Pad data shorter than data_length with zeros
+        let gap = self.data_length as usize - data_bytes;
+        let v: Vec<T> = vec![Default::default(); gap];
+        target_bv.extend_from_raw_slice(v.as_slice());
+        Ok(())
+    }
+
+    fn deserialize(&mut self, msg: &[u8], pos: &mut usize) -> Result<(), DnsParseError> {
+        self.rname.deserialize(msg, pos)?;
+        self.rtype.deserialize(msg, pos)?;
+        self.rclass.deserialize(msg, pos)?;
+        self.ttl = read_u32(msg, pos, DnsParseError::TruncatedRecord)?;
+        self.data_length = read_u16(msg, pos, DnsParseError::TruncatedRecord)?;
+        let offset = *pos;
+        let data = read_bytes(
+            msg,
+            pos,
+            self.data_length as usize,
+            DnsParseError::TruncatedRdata,
+        )?;
+        match self.rtype {
+            RecordType::TXT if character_strings(data).is_none() => {
+                return Err(DnsParseError::TruncatedRdata(offset));
+            }
+            // Names in the rdata may point into the rest of the message, so
+            // they're written out in full for the record to stand on its own
+            RecordType::CNAME | RecordType::MX | RecordType::SRV => {
+                let mut rdata_pos = offset;
+                let rdata =
+                    Rdata::deserialize(self.rtype, msg, &mut rdata_pos, data.len())?.serialize()?;
+                self.data_length = (rdata.len() / 8) as u16;
+                self.data = rdata;
+                return Ok(());
+            }
+            _ => (),
+        }
+        self.data = BitVec::from_slice(data);
+        Ok(())
+    }
+
+    fn rdata(&self) -> Result<Rdata, DnsParseError> {
+        let data = self.data.as_raw_slice();
+        Rdata::deserialize(self.rtype, data, &mut 0, data.len())
+    }
+
+    // Position of the record among those carrying tunnel data in an RRset,
+    // which resolvers may shuffle. MX preference and SRV priority both come
+    // first in the rdata, addresses number their records in the last byte.
+    fn sequence(&self) -> Option<u16> {
+        let data = self.data.as_raw_slice();
+        match self.rtype {
+            RecordType::MX | RecordType::SRV => {
+                Some(u16::from_be_bytes([*data.first()?, *data.get(1)?]))
+            }
+            RecordType::A | RecordType::AAAA => data.last().map(|&index| index as u16),
+            _ => None,
+        }
+    }
+}
+
+// Rdata of the record types which can carry tunnel data in a name, or in any
+// bytes at all for NULL
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Rdata {
+    Cname(DnsName),
+    Null(Vec<u8>),
+    Mx {
+        preference: u16,
+        exchange: DnsName,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName,
+    },
+}
+
+impl Rdata {
+    fn serialize(&self) -> Result<BitVec<u8, Msb0>, DnsParseError> {
+        let mut rdata = bitvec![u8, Msb0;];
+        match self {
+            Rdata::Cname(name) => rdata.extend_from_bitslice(&name_rdata(name)?),
+            Rdata::Null(data) => rdata.extend_from_raw_slice(data),
+            Rdata::Mx {
+                preference,
+                exchange,
+            } => {
+                rdata.extend_from_bitslice(preference.view_bits::<Msb0>());
+                rdata.extend_from_bitslice(&name_rdata(exchange)?);
+            }
+            Rdata::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for value in [priority, weight, port] {
+                    rdata.extend_from_bitslice(value.view_bits::<Msb0>());
+                }
+                rdata.extend_from_bitslice(&name_rdata(target)?);
+            }
+        }
+        Ok(rdata)
+    }
+
+    // Parse the `len` bytes of rdata at `pos`, which names may point back from
+    fn deserialize(
+        rtype: RecordType,
+        msg: &[u8],
+        pos: &mut usize,
+        len: usize,
+    ) -> Result<Self, DnsParseError> {
+        let offset = *pos;
+        let msg = msg
+            .get(..offset + len)
+            .ok_or(DnsParseError::TruncatedRdata(offset))?;
+        let read_name = |pos: &mut usize| {
+            let mut name = DnsName(vec![]);
+            name.deserialize(msg, pos).map(|_| name)
+        };
+        let rdata = match rtype {
+            RecordType::CNAME => Rdata::Cname(read_name(pos)?),
+            RecordType::NULL => {
+                Rdata::Null(read_bytes(msg, pos, len, DnsParseError::TruncatedRdata)?.to_vec())
+            }
+            RecordType::MX => Rdata::Mx {
+                preference: read_u16(msg, pos, DnsParseError::TruncatedRdata)?,
+                exchange: read_name(pos)?,
+            },
+            RecordType::SRV => Rdata::Srv {
+                priority: read_u16(msg, pos, DnsParseError::TruncatedRdata)?,
+                weight: read_u16(msg, pos, DnsParseError::TruncatedRdata)?,
+                port: read_u16(msg, pos, DnsParseError::TruncatedRdata)?,
+                target: read_name(pos)?,
+            },
+            other => return Err(DnsParseError::UnsupportedRecordType(other.value())),
+        };
+        // Anything after the name doesn't belong there
+        if *pos != offset + len {
+            return Err(DnsParseError::BadRdata(offset));
+        }
+        Ok(rdata)
+    }
+
+    // Name carrying tunnel data
+    fn name(&self) -> Option<&DnsName> {
+        match self {
+            Rdata::Cname(name) => Some(name),
+            Rdata::Mx { exchange, .. } => Some(exchange),
+            Rdata::Srv { target, .. } => Some(target),
+            Rdata::Null(_) => None,
+        }
+    }
+}
+
+// Data in names goes in labels of up to 63 characters in front of the tunnel
+// domain: the tag of the codec, then the data it encoded. Names without data
+// have no labels of their own.
+fn data_name(data: &[u8], domain: &DnsName, codec: &dyn LabelCodec) -> DnsName {
+    let mut text = vec![codec.tag()];
+    text.extend(codec.encode(data).chars());
+    let mut labels: Vec<String> = if data.is_empty() {
+        Vec::new()
+    } else {
+        text.chunks(MAX_LABEL_LEN)
+            .map(|label| label.iter().collect())
+            .collect()
+    };
+    labels.extend_from_slice(&domain.0);
+    DnsName(labels)
+}
+
+// Data carried by the labels of `name` in front of `domain`, in whatever
+// codec they name
+fn name_data(name: &DnsName, domain: &DnsName) -> Result<Vec<u8>, DnsParseError> {
+    let text = name
+        .strip_suffix(domain)
+        .ok_or_else(|| DnsParseError::NameOutsideZone(name.to_string()))?
+        .concat();
+    let mut chars = text.chars();
+    let Some(tag) = chars.next() else {
+        return Ok(Vec::new());
+    };
+    codec::by_tag(tag)
+        .and_then(|codec| codec.decode(chars.as_str()))
+        .ok_or(DnsParseError::UndecodableLabel(text))
+}
+
+// Codec of the data in `name`, if it carries any
+fn name_codec(name: &DnsName, domain: &DnsName) -> Option<&'static dyn LabelCodec> {
+    let tag = name.strip_suffix(domain)?.first()?.chars().next()?;
+    codec::by_tag(tag)
+}
+
+// Number of bytes `data_name` fits in labels taking at most `wire_len` bytes
+fn data_name_capacity(wire_len: usize, codec: &dyn LabelCodec) -> usize {
+    let label_len = MAX_LABEL_LEN + 1;
+    let chars = MAX_LABEL_LEN * (wire_len / label_len) + (wire_len % label_len).saturating_sub(1);
+    codec.decoded_len(chars.saturating_sub(1))
+}
+
+// Bytes taken by the labels `data_name` puts `len` bytes of data in
+fn data_labels_len(len: usize, codec: &dyn LabelCodec) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    let chars = 1 + codec.encoded_len(len);
+    chars + chars.div_ceil(MAX_LABEL_LEN)
+}
+
+// Resolvers only pass the answer section on, so all data goes in there. A and
+// AAAA answers carry it in all but the last byte of the address, which numbers
+// the answer, and an RRset of them can't have more than this many.
+const MAX_ADDRESS_ANSWERS: usize = 256;
+
+// Bytes of data an A or AAAA answer carries
+fn address_data_len(qtype: RecordType) -> Option<usize> {
+    match qtype {
+        RecordType::A => Some(3),
+        RecordType::AAAA => Some(15),
+        _ => None,
+    }
+}
+
+// Bytes taken by an answer to a question of `qtype`, with its name pointing
+// to the question, and with no more data than every answer of the type holds
+fn answer_len(qtype: RecordType, domain: &DnsName) -> Option<usize> {
+    let rdata_len = match qtype {
+        RecordType::A => 4,
+        RecordType::AAAA => 16,
+        RecordType::TXT => 1,
+        RecordType::NULL => 0,
+        RecordType::CNAME => domain.wire_len(),
+        RecordType::MX => 2 + domain.wire_len(),
+        RecordType::SRV => 6 + domain.wire_len(),
+        _ => return None,
+    };
+    Some(12 + rdata_len)
+}
+
+// TXT rdata is a sequence of character-strings, each a length byte followed
+// by up to 255 bytes. There is always at least one, if only an empty one.
+const MAX_STRING_LEN: usize = 255;
+
+fn character_strings_rdata(data: &[u8]) -> BitVec<u8, Msb0> {
+    let mut rdata = Vec::with_capacity(data.len() + data.len() / MAX_STRING_LEN + 1);
+    for string in data.chunks(MAX_STRING_LEN) {
+        rdata.push(string.len() as u8);
+        rdata.extend_from_slice(string);
+    }
+    if data.is_empty() {
+        rdata.push(0);
+    }
+    BitVec::from_vec(rdata)
+}
+
+// Concatenated contents of the character-strings in `rdata`, unless one of
+// them runs past its end
+fn character_strings(mut rdata: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(rdata.len());
+    while let Some((&len, rest)) = rdata.split_first() {
+        let string = rest.get(..len as usize)?;
+        data.extend_from_slice(string);
+        rdata = &rest[len as usize..];
+    }
+    Some(data)
+}
+
+// EDNS(0) OPT pseudo-record (RFC 6891). Its class is the UDP payload size the
+// sender takes, and its TTL holds the upper bits of the response code, the
+// version and the DO flag. Kept apart from the additional records it's sent
+// with, the response code is the packet's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+// Bytes taken by an OPT record without options
+pub const OPT_LEN: usize = 11;
+
+impl Edns {
+    const TYPE: u16 = 41;
+    const FLAG_DNSSEC_OK: u32 = 0x8000;
+
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    fn serialize<T: BitStore>(
+        &self,
+        target_bv: &mut BitVec<T, Msb0>,
+        extended_rcode: u8,
+    ) -> Result<(), DnsParseError> {
+        let mut ttl = (extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= Self::FLAG_DNSSEC_OK;
+        }
+        let mut rdata = Vec::new();
+        for option in &self.options {
+            let len = u16::try_from(option.data.len()).map_err(|_| {
+                DnsParseError::DataExceedMaxLen(u16::MAX as usize, option.data.len())
+            })?;
+            rdata.extend_from_slice(&option.code.to_be_bytes());
+            rdata.extend_from_slice(&len.to_be_bytes());
+            rdata.extend_from_slice(&option.data);
+        }
+        let rdata_len = u16::try_from(rdata.len())
+            .map_err(|_| DnsParseError::DataExceedMaxLen(u16::MAX as usize, rdata.len()))?;
+        // Owned by the root
+        target_bv.extend_from_bitslice(0u8.view_bits::<Msb0>());
+        target_bv.extend_from_bitslice(Self::TYPE.view_bits::<Msb0>());
+        target_bv.extend_from_bitslice(self.udp_payload_size.view_bits::<Msb0>());
+        target_bv.extend_from_bitslice(ttl.view_bits::<Msb0>());
+        target_bv.extend_from_bitslice(rdata_len.view_bits::<Msb0>());
+        target_bv.extend_from_bitslice(rdata.view_bits::<Msb0>());
         Ok(())
     }
+
+    // Read the OPT record at `pos` along with the upper bits of the response
+    // code, or leave `pos` alone if the record there is another one
+    fn deserialize(msg: &[u8], pos: &mut usize) -> Result<Option<(Self, u8)>, DnsParseError> {
+        let offset = *pos;
+        let mut cursor = offset;
+        let mut name = DnsName(Vec::new());
+        name.deserialize(msg, &mut cursor)?;
+        if read_u16(msg, &mut cursor, DnsParseError::TruncatedRecord)? != Self::TYPE {
+            return Ok(None);
+        }
+        if !name.0.is_empty() {
+            return Err(DnsParseError::BadOpt(offset));
+        }
+        let udp_payload_size = read_u16(msg, &mut cursor, DnsParseError::TruncatedRecord)?;
+        let ttl = read_u32(msg, &mut cursor, DnsParseError::TruncatedRecord)?;
+        let rdata_len = read_u16(msg, &mut cursor, DnsParseError::TruncatedRecord)?;
+        let mut rdata = read_bytes(
+            msg,
+            &mut cursor,
+            rdata_len as usize,
+            DnsParseError::TruncatedRdata,
+        )?;
+        let mut options = Vec::new();
+        while !rdata.is_empty() {
+            let Some((header, rest)) = rdata.split_first_chunk::<4>() else {
+                return Err(DnsParseError::BadOpt(offset));
+            };
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            if rest.len() < len {
+                return Err(DnsParseError::BadOpt(offset));
+            }
+            options.push(EdnsOption {
+                code,
+                data: rest[..len].to_vec(),
+            });
+            rdata = &rest[len..];
+        }
+        *pos = cursor;
+        let edns = Edns {
+            udp_payload_size,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & Self::FLAG_DNSSEC_OK != 0,
+            options,
+        };
+        Ok(Some((edns, (ttl >> 24) as u8)))
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Default, PartialEq, Eq, Debug)]
 pub struct Packet {
     header: Header,
 
@@ -353,13 +941,57 @@ pub struct Packet {
 
     additional: Vec<Record>,
 
+    edns: Option<Edns>,
+
     is_response: bool,
+
+    authoritative: bool,
+
+    truncated: bool,
+
+    recursion_desired: bool,
+
+    rcode: ResponseCode,
+}
+
+// Values in the SOA record of the tunnel zone. Negative answers must not be
+// cached, as every query name is only ever asked once.
+const SOA_SERIAL: u32 = 1;
+const SOA_REFRESH: u32 = 3600;
+const SOA_RETRY: u32 = 600;
+const SOA_EXPIRE: u32 = 86400;
+const SOA_MINIMUM: u32 = 0;
+const ZONE_TTL: u32 = 3600;
+
+fn name_rdata(name: &DnsName) -> Result<BitVec<u8, Msb0>, DnsParseError> {
+    // Names in rdata are written out in full, pointers are only used for owner names
+    let mut rdata = bitvec![u8, Msb0;];
+    name.serialize(&mut rdata, &mut CompressionMap::new())?;
+    Ok(rdata)
+}
+
+fn soa_record(domain: &DnsName, nameserver: &DnsName) -> Result<Record, DnsParseError> {
+    let mut hostmaster = domain.clone();
+    hostmaster.0.insert(0, String::from("hostmaster"));
+    let mut rdata = name_rdata(nameserver)?;
+    rdata.extend_from_bitslice(&name_rdata(&hostmaster)?);
+    for value in [SOA_SERIAL, SOA_REFRESH, SOA_RETRY, SOA_EXPIRE, SOA_MINIMUM] {
+        rdata.extend_from_bitslice(value.view_bits::<Msb0>());
+    }
+    Ok(Record {
+        rname: domain.clone(),
+        rtype: RecordType::SOA,
+        rclass: RecordClass::IN,
+        ttl: ZONE_TTL,
+        data_length: (rdata.len() / 8) as u16,
+        data: rdata,
+    })
 }
 
 impl Packet {
     pub fn new(is_response: bool) -> Self {
         Packet {
-            is_response: is_response,
+            is_response,
             ..Self::default()
         }
     }
@@ -368,161 +1000,519 @@ impl Packet {
         // Fill id and length fields in header
         let try_usize_to_u16 = |x: usize| match x.try_into() {
             Ok(y) => Ok(y),
-            Err(_) => Err(DnsParseError::DataExceedMaxLen(std::u16::MAX as usize, x)),
+            Err(_) => Err(DnsParseError::DataExceedMaxLen(u16::MAX as usize, x)),
         };
         self.header.id = id;
         self.header.questions_count = try_usize_to_u16(self.questions.len())?;
         self.header.answers_count = try_usize_to_u16(self.answers.len())?;
         self.header.authorities_count = try_usize_to_u16(self.authorities.len())?;
-        self.header.additional_count = try_usize_to_u16(self.additional.len())?;
-        self.header.flags = if self.is_response {
-            Header::FLAG_RESPONSE
-        } else {
-            0
-        };
+        self.header.additional_count =
+            try_usize_to_u16(self.additional.len() + self.edns.is_some() as usize)?;
+        self.header.flags = self.rcode.value() & Header::RCODE_MASK;
+        if self.is_response {
+            self.header.flags |= Header::FLAG_RESPONSE;
+        }
+        if self.authoritative {
+            self.header.flags |= Header::FLAG_AUTHORITATIVE;
+        }
+        if self.truncated {
+            self.header.flags |= Header::FLAG_TRUNCATED;
+        }
+        if self.recursion_desired {
+            self.header.flags |= Header::FLAG_RECURSION_DESIRED;
+        }
+        Ok(())
+    }
+
+    // Skeleton of an authoritative response to `request`, with the question
+    // echoed back exactly as asked.
+    pub fn response_to(request: &Packet) -> Self {
+        Packet {
+            questions: request.questions.clone(),
+            is_response: true,
+            authoritative: true,
+            recursion_desired: request.recursion_desired,
+            // EDNS is only used with those who use it
+            edns: request
+                .edns
+                .as_ref()
+                .map(|_| Edns::new(MAX_EDNS_UDP_LEN as u16)),
+            ..Self::default()
+        }
+    }
+
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    pub fn set_edns(&mut self, edns: Option<Edns>) {
+        self.edns = edns;
+    }
+
+    // Largest response the sender of this query takes, as far as we go
+    pub fn max_response_len(&self) -> usize {
+        self.edns.as_ref().map_or(MAX_UDP_LEN, |edns| {
+            (edns.udp_payload_size as usize).clamp(MAX_UDP_LEN, MAX_EDNS_UDP_LEN)
+        })
+    }
+
+    pub fn add_question(&mut self, qname: DnsName, qtype: RecordType) {
+        self.questions.push(Question {
+            qname,
+            qtype,
+            qclass: RecordClass::IN,
+        });
+    }
+
+    pub fn id(&self) -> u16 {
+        self.header.id
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.is_response
+    }
+
+    pub fn rcode(&self) -> ResponseCode {
+        self.rcode
+    }
+
+    pub fn set_rcode(&mut self, rcode: ResponseCode) {
+        self.rcode = rcode;
+    }
+
+    // Whether the response didn't fit in a datagram, and the query should be
+    // asked again over TCP
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.truncated = truncated;
+    }
+
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.recursion_desired = recursion_desired;
+    }
+
+    // Ask every question for records of `qtype`, which is what the data in the
+    // response will be carried in
+    pub fn set_query_type(&mut self, qtype: RecordType) {
+        for question in &mut self.questions {
+            question.qtype = qtype;
+        }
+    }
+
+    // Type of the records asked for by the first question
+    pub fn query_type(&self) -> Option<RecordType> {
+        self.questions.first().map(|q| q.qtype)
+    }
+
+    // Whether any question is asked, and all of them are at or under `domain`
+    pub fn in_zone(&self, domain: &DnsName) -> bool {
+        !self.questions.is_empty()
+            && self
+                .questions
+                .iter()
+                .all(|q| q.qname.strip_suffix(domain).is_some())
+    }
+
+    // Whether all questions are in the Internet class, the only one our zone
+    // is in
+    pub fn is_internet_class(&self) -> bool {
+        self.questions.iter().all(|q| q.qclass == RecordClass::IN)
+    }
+
+    // Whether all questions ask for records which can carry tunnel data.
+    // Resolvers ask for others too, e.g. HTTPS or DS, which the zone just
+    // doesn't have.
+    pub fn asks_for_data(&self) -> bool {
+        self.questions.iter().all(|q| q.qtype.carries_data())
+    }
+
+    // Whether all questions are about `domain` itself rather than tunnel data
+    pub fn is_apex_query(&self, domain: &DnsName) -> bool {
+        !self.questions.is_empty()
+            && self
+                .questions
+                .iter()
+                .all(|q| q.qname.strip_suffix(domain).is_some_and(|p| p.is_empty()))
+    }
+
+    // Identity of the questions, stable across retries of the same query by a
+    // resolver. Letter case is ignored unless the codec of the data in the
+    // names under `domain` tells data apart by it.
+    pub fn question_key(&self, domain: &DnsName) -> String {
+        let ignore_case = self
+            .codec(domain)
+            .is_none_or(|codec| !codec.case_sensitive());
+        self.questions
+            .iter()
+            .map(|q| {
+                let name = q.qname.to_string();
+                let name = if ignore_case {
+                    name.to_ascii_lowercase()
+                } else {
+                    name
+                };
+                format!("{}/{}", name, q.qtype.value())
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Answer SOA and NS questions about the zone apex. Other types get an empty
+    // answer with the SOA in the authority section.
+    pub fn answer_apex(
+        &mut self,
+        domain: &DnsName,
+        nameserver: &DnsName,
+    ) -> Result<(), DnsParseError> {
+        for question in &self.questions {
+            match question.qtype {
+                RecordType::SOA => self.answers.push(soa_record(domain, nameserver)?),
+                RecordType::NS => {
+                    let rdata = name_rdata(nameserver)?;
+                    self.answers.push(Record {
+                        rname: question.qname.clone(),
+                        rtype: RecordType::NS,
+                        rclass: RecordClass::IN,
+                        ttl: ZONE_TTL,
+                        data_length: (rdata.len() / 8) as u16,
+                        data: rdata,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if self.answers.is_empty() {
+            self.set_nodata(domain, nameserver)?;
+        }
+        Ok(())
+    }
+
+    // Mark the response as a name which exists but has no data of the asked type
+    pub fn set_nodata(
+        &mut self,
+        domain: &DnsName,
+        nameserver: &DnsName,
+    ) -> Result<(), DnsParseError> {
+        self.rcode = ResponseCode::NoError;
+        self.answers.clear();
+        self.additional.clear();
+        self.authorities = vec![soa_record(domain, nameserver)?];
         Ok(())
     }
 
     pub fn serialize(&mut self, id: u16) -> Result<BitVec<u8, Msb0>, DnsParseError> {
         let mut buf = bitvec![u8, Msb0;];
+        let mut names = CompressionMap::new();
         self.header_gen(id)?;
 
         self.header.serialize(&mut buf);
-        self.questions.iter().for_each(|q| q.serialize(&mut buf));
-        self.answers.iter().for_each(|a| a.serialize(&mut buf));
-        self.authorities.iter().for_each(|a| a.serialize(&mut buf));
-        self.additional.iter().for_each(|a| a.serialize(&mut buf));
+        for q in &self.questions {
+            q.serialize(&mut buf, &mut names)?;
+        }
+        for a in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additional)
+        {
+            a.serialize(&mut buf, &mut names)?;
+        }
+        if let Some(edns) = &self.edns {
+            edns.serialize(&mut buf, (self.rcode.value() >> 4) as u8)?;
+        }
 
         Ok(buf)
     }
 
-    pub fn deserialize<'a, I>(&mut self, mut iter: I) -> Result<(), DnsParseError>
-    where
-        I: Iterator<Item = &'a u8>,
-    {
-        self.header.deserialize(&mut iter)?;
+    pub fn deserialize(&mut self, msg: &[u8]) -> Result<(), DnsParseError> {
+        let mut pos = 0;
+        self.header.deserialize(msg, &mut pos)?;
         let to_modify = [
             (self.header.answers_count, &mut self.answers),
             (self.header.authorities_count, &mut self.authorities),
-            (self.header.additional_count, &mut self.additional),
         ];
         for _ in 0..self.header.questions_count {
             let mut q = Question::new();
-            q.deserialize(&mut iter)?;
+            q.deserialize(msg, &mut pos)?;
             self.questions.push(q);
         }
         for (count, modify) in to_modify {
             for _ in 0..count {
                 let mut a = Record::new();
-                a.deserialize(&mut iter)?;
+                a.deserialize(msg, &mut pos)?;
                 modify.push(a);
             }
         }
+        // Only one OPT record is allowed, anywhere among the additional ones
+        let mut extended_rcode = 0;
+        for _ in 0..self.header.additional_count {
+            let offset = pos;
+            if let Some((edns, rcode)) = Edns::deserialize(msg, &mut pos)? {
+                if self.edns.replace(edns).is_some() {
+                    return Err(DnsParseError::BadOpt(offset));
+                }
+                extended_rcode = rcode;
+                continue;
+            }
+            let mut a = Record::new();
+            a.deserialize(msg, &mut pos)?;
+            self.additional.push(a);
+        }
         if self.header.flags & Header::FLAG_RESPONSE == Header::FLAG_RESPONSE {
             self.is_response = true;
         }
+        self.authoritative = self.header.flags & Header::FLAG_AUTHORITATIVE != 0;
+        self.truncated = self.header.flags & Header::FLAG_TRUNCATED != 0;
+        self.recursion_desired = self.header.flags & Header::FLAG_RECURSION_DESIRED != 0;
+        self.rcode = ResponseCode::from_value(
+            (extended_rcode as u16) << 4 | self.header.flags & Header::RCODE_MASK,
+        );
         Ok(())
     }
 
-    pub fn embed_data(&mut self, data: &[u8], request: Option<&Packet>) -> Result<(), DnsParseError> {
+    // Number of data bytes `embed_data` can put in a query under `domain`,
+    // keeping the response answering it with a record of `qtype` within
+    // `max_len` bytes. The data goes in the labels of the single question,
+    // which the response echoes, in front of the domain.
+    pub fn query_capacity(
+        domain: &DnsName,
+        qtype: RecordType,
+        codec: &dyn LabelCodec,
+        max_len: usize,
+    ) -> usize {
+        let Some(answer_len) = answer_len(qtype, domain) else {
+            return 0;
+        };
+        let name_room = max_len.saturating_sub(12 + domain.wire_len() + 4 + answer_len);
+        let labels_len = name_room.min(MAX_NAME_LEN.saturating_sub(domain.wire_len()));
+        data_name_capacity(labels_len, codec)
+    }
+
+    // Codec of the data in the questions
+    pub fn codec(&self, domain: &DnsName) -> Option<&'static dyn LabelCodec> {
+        name_codec(&self.questions.first()?.qname, domain)
+    }
+
+    // Number of data bytes `embed_data` can put in the response to this query,
+    // keeping the serialized response within `max_len` bytes.
+    pub fn response_capacity(
+        &self,
+        domain: &DnsName,
+        codec: &dyn LabelCodec,
+        max_len: usize,
+    ) -> usize {
+        let mut used = match Packet::response_to(self).serialize(0) {
+            Ok(buf) => buf.len() / 8,
+            Err(_) => return 0,
+        };
+        let mut capacity = 0;
+        // Answer names point to the question
+        for question in &self.questions {
+            let Some(answer_len) = answer_len(question.qtype, domain) else {
+                return 0;
+            };
+            used += answer_len;
+            capacity += address_data_len(question.qtype).unwrap_or(0);
+        }
+        let Some(mut rest) = max_len.checked_sub(used) else {
+            return 0;
+        };
+
+        // Answers take more data in the order `embed_data` fills them
+        let full_name = data_name_capacity(MAX_NAME_LEN.saturating_sub(domain.wire_len()), codec);
+        for question in &self.questions {
+            let (data_len, record_len) = match question.qtype {
+                // Strings in the TXT answer, which paid for one length byte
+                RecordType::TXT => {
+                    let len = rest + 1;
+                    let data_len = MAX_STRING_LEN * (len / 256) + (len % 256).saturating_sub(1);
+                    (data_len, rest)
+                }
+                RecordType::NULL => (rest, rest),
+                RecordType::CNAME => {
+                    let data_len = data_name_capacity(rest, codec).min(full_name);
+                    (data_len, data_labels_len(data_len, codec))
+                }
+                // The name of the first answer, then more answers
+                RecordType::MX | RecordType::SRV => {
+                    let mut data_len = data_name_capacity(rest, codec).min(full_name);
+                    let mut left = rest - data_labels_len(data_len, codec);
+                    let per_answer = answer_len(question.qtype, domain).unwrap_or(usize::MAX);
+                    while data_len > 0 && data_len.is_multiple_of(full_name) {
+                        let Some(budget) = left.checked_sub(per_answer) else {
+                            break;
+                        };
+                        let more = data_name_capacity(budget, codec).min(full_name);
+                        if more == 0 {
+                            break;
+                        }
+                        data_len += more;
+                        left = budget - data_labels_len(more, codec);
+                    }
+                    // Anything left over would go in another answer as well
+                    (data_len, rest)
+                }
+                // As many more answers as fit
+                RecordType::A | RecordType::AAAA => {
+                    let per_answer = answer_len(question.qtype, domain).unwrap_or(usize::MAX);
+                    let more = (rest / per_answer).min(MAX_ADDRESS_ANSWERS - 1);
+                    let data_len = address_data_len(question.qtype).unwrap_or(0);
+                    (more * data_len, more * per_answer)
+                }
+                _ => (0, 0),
+            };
+            capacity += data_len;
+            rest -= record_len;
+        }
+        capacity
+    }
+
+    pub fn embed_data(
+        &mut self,
+        data: &[u8],
+        request: Option<&Packet>,
+        domain: &DnsName,
+        codec: &dyn LabelCodec,
+    ) -> Result<(), DnsParseError> {
         // If packet is request, then embed data into prefix of query name
-        // else embed data into ip address of answers(or additional if query is inadequate)
+        // else embed data into the answers to its questions
         if self.is_response {
             match request {
                 Some(req) => {
                     self.questions = req.questions.clone();
-                },
-                None => {
-                    panic!("request not provided for response message!")
                 }
+                None => return Err(DnsParseError::StreamFormatError),
             };
             let mut data_iter = data.iter().peekable();
-            // TODO: Alignment
+            let full_name =
+                data_name_capacity(MAX_NAME_LEN.saturating_sub(domain.wire_len()), codec);
+            // Position of the next MX or SRV answer
+            let mut sequence: u16 = 0;
             for question in &self.questions {
-                let chunk_size: u16 = match question.qtype {
-                    RecordType::A => 4,
-                    RecordType::AAAA => 16,
+                // Rdata of the answers, A and AAAA ones padded
+                let mut answers: Vec<BitVec<u8, Msb0>> = Vec::new();
+                match question.qtype {
+                    // As many answers as the data takes, numbered in order
+                    RecordType::A | RecordType::AAAA => loop {
+                        let len = address_data_len(question.qtype).unwrap_or(0);
+                        let index = u8::try_from(sequence).map_err(|_| {
+                            DnsParseError::DataExceedMaxLen(MAX_ADDRESS_ANSWERS * len, data.len())
+                        })?;
+                        let mut address: Vec<u8> = (&mut data_iter).take(len).copied().collect();
+                        address.resize(len, 0);
+                        address.push(index);
+                        sequence = sequence.wrapping_add(1);
+                        answers.push(BitVec::from_vec(address));
+                        if data_iter.peek().is_none() {
+                            break;
+                        }
+                    },
+                    // The first TXT or NULL answer takes everything left
+                    RecordType::TXT => {
+                        let rest: Vec<u8> = (&mut data_iter).copied().collect();
+                        answers.push(character_strings_rdata(&rest));
+                    }
+                    RecordType::NULL => {
+                        let rest: Vec<u8> = (&mut data_iter).copied().collect();
+                        answers.push(Rdata::Null(rest).serialize()?);
+                    }
+                    RecordType::CNAME => {
+                        let chunk: Vec<u8> = (&mut data_iter).take(full_name).copied().collect();
+                        let rdata = Rdata::Cname(data_name(&chunk, domain, codec));
+                        answers.push(rdata.serialize()?);
+                    }
+                    // As many answers as the data takes, in an order of their own
+                    RecordType::MX | RecordType::SRV => loop {
+                        let chunk: Vec<u8> = (&mut data_iter).take(full_name).copied().collect();
+                        let name = data_name(&chunk, domain, codec);
+                        let rdata = match question.qtype {
+                            RecordType::MX => Rdata::Mx {
+                                preference: sequence,
+                                exchange: name,
+                            },
+                            _ => Rdata::Srv {
+                                priority: sequence,
+                                weight: 0,
+                                port: 0,
+                                target: name,
+                            },
+                        };
+                        sequence = sequence.wrapping_add(1);
+                        answers.push(rdata.serialize()?);
+                        if chunk.is_empty() || data_iter.peek().is_none() {
+                            break;
+                        }
+                    },
+                    other => return Err(DnsParseError::UnsupportedRecordType(other.value())),
                 };
-                self.answers.push(Record {
-                    rname: question.qname.clone(),
-                    rtype: question.qtype,
-                    rclass: question.qclass,
-                    // TODO: Random ttl?
-                    ttl: 256,
-                    data_length: chunk_size,
-                    data: (&mut data_iter).take(chunk_size as usize).collect::<BitVec<u8, Msb0>>(),
-                });
-            }
-            while data_iter.peek() != None {
-                self.additional.push(Record {
-                    rname: DnsName::Str(vec![String::from("reply"), String::from("com")]),
-                    rtype: RecordType::AAAA,
-                    rclass: RecordClass::IN,
-                    // TODO: Random ttl?
-                    ttl: 256,
-                    data_length: 16,
-                    data: (&mut data_iter).take(16).collect(),
-                });
+                for rdata in answers {
+                    let data_length = u16::try_from(rdata.len() / 8).map_err(|_| {
+                        DnsParseError::DataExceedMaxLen(u16::MAX as usize, rdata.len() / 8)
+                    })?;
+                    self.answers.push(Record {
+                        rname: question.qname.clone(),
+                        rtype: question.qtype,
+                        rclass: question.qclass,
+                        // Tunnel answers are only valid once, keep resolvers from caching them
+                        ttl: 0,
+                        data_length,
+                        data: rdata,
+                    });
+                }
             }
         } else {
-            for data_chunk in data.chunks(5) {
-                self.questions.push(Question {
-                    qname: DnsName::Str(vec![
-                        BASE32_DNSSEC.encode(data_chunk),
-                        String::from("baidu"),
-                        String::from("com"),
-                    ]),
-                    qtype: RecordType::A,
-                    qclass: RecordClass::IN,
-                });
+            // Resolvers only take queries with a single question
+            let capacity =
+                data_name_capacity(MAX_NAME_LEN.saturating_sub(domain.wire_len()), codec);
+            if data.len() > capacity {
+                return Err(DnsParseError::DataExceedMaxLen(capacity, data.len()));
             }
+            self.questions.push(Question {
+                qname: data_name(data, domain, codec),
+                qtype: RecordType::A,
+                qclass: RecordClass::IN,
+            });
         }
         Ok(())
     }
 
-    pub fn extract_data(&mut self) -> Vec<u8> {
+    pub fn extract_data(&self, domain: &DnsName) -> Result<Vec<u8>, DnsParseError> {
         let mut data = Vec::new();
         if self.is_response {
-            for answer in &self.answers {
-                data.extend_from_slice(answer.data.as_raw_slice())
-            }
-            for additional in &self.additional {
-                data.extend_from_slice(additional.data.as_raw_slice())
+            let mut answers: Vec<&Record> = self.answers.iter().collect();
+            answers.sort_by_key(|answer| answer.sequence());
+            for answer in answers {
+                match answer.rtype {
+                    RecordType::TXT => data.append(
+                        &mut character_strings(answer.data.as_raw_slice())
+                            .ok_or(DnsParseError::StreamFormatError)?,
+                    ),
+                    RecordType::CNAME | RecordType::MX | RecordType::SRV => {
+                        let rdata = answer.rdata()?;
+                        let name = rdata.name().ok_or(DnsParseError::StreamFormatError)?;
+                        data.append(&mut name_data(name, domain)?);
+                    }
+                    // Everything but the number of the answer
+                    RecordType::A | RecordType::AAAA => {
+                        let address = answer.data.as_raw_slice();
+                        data.extend_from_slice(&address[..address.len().saturating_sub(1)]);
+                    }
+                    _ => data.extend_from_slice(answer.data.as_raw_slice()),
+                }
             }
         } else {
             for q in &self.questions {
-                let data_str = match &q.qname {
-                    DnsName::Str(str) => str,
-                    DnsName::Offset(_) => panic!("Embeded data can't use offset"),
-                };
-                data.append(
-                    &mut BASE32_DNSSEC
-                        .decode(data_str[0].as_bytes())
-                        .expect("Error decoding"),
-                );
+                data.append(&mut name_data(&q.qname, domain)?);
             }
         }
-        data
-    }
-}
-
-impl Default for Packet {
-    fn default() -> Self {
-        Packet {
-            header: Default::default(),
-            questions: Vec::new(),
-            answers: Vec::new(),
-            authorities: Vec::new(),
-            additional: Vec::new(),
-
-            is_response: false
-        }
+        Ok(data)
     }
 }
 
 // Tests
-use std::net::{SocketAddrV4, UdpSocket};
-use std::str::FromStr;
 
 #[test]
 fn check_request() -> Result<(), Box<dyn error::Error>> {
@@ -548,7 +1538,7 @@ fn check_request() -> Result<(), Box<dyn error::Error>> {
     // let dest = SocketAddrV4::from_str("127.0.0.1:53").unwrap();
     // socket.send_to(buf.as_raw_slice(), dest)?;
     let mut p_check = Packet::new(false);
-    p_check.deserialize(buf.iter())?;
+    p_check.deserialize(buf)?;
     assert_eq!(p_check, p);
     Ok(())
 }
@@ -557,3 +1547,493 @@ fn check_request() -> Result<(), Box<dyn error::Error>> {
 fn check_response() -> Result<(), Box<dyn error::Error>> {
     Ok(())
 }
+
+#[test]
+fn check_name_compression() -> Result<(), Box<dyn error::Error>> {
+    let name = DnsName::from_str("abc.xyz.com")?;
+    let mut p = Packet {
+        questions: vec![Question {
+            qname: name.clone(),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        }],
+        answers: vec![Record {
+            rname: DnsName::from_str("ABC.xyz.com")?,
+            rtype: RecordType::A,
+            rclass: RecordClass::IN,
+            ttl: 256,
+            data_length: 4,
+            data: BitVec::from_slice(&[1, 2, 3, 4]),
+        }],
+        additional: vec![Record {
+            rname: DnsName::from_str("def.xyz.com")?,
+            rtype: RecordType::A,
+            rclass: RecordClass::IN,
+            ttl: 256,
+            data_length: 4,
+            data: BitVec::from_slice(&[5, 6, 7, 8]),
+        }],
+        is_response: true,
+        ..Default::default()
+    };
+    let binding = p.serialize(1)?;
+    let buf = binding.as_raw_slice();
+    // Answer name is a pointer to the question name right after the header
+    assert_eq!(&buf[29..31], [0xC0, 12]);
+    // Additional name only shares the "xyz.com" suffix
+    assert_eq!(&buf[45..51], [3, b'd', b'e', b'f', 0xC0, 16]);
+
+    let mut p_check = Packet::new(true);
+    p_check.deserialize(buf)?;
+    assert_eq!(p_check.answers[0].rname, name);
+    assert_eq!(
+        p_check.additional[0].rname,
+        DnsName::from_str("def.xyz.com")?
+    );
+    assert_eq!(p_check.additional[0].data.as_raw_slice(), [5, 6, 7, 8]);
+    Ok(())
+}
+
+#[test]
+fn check_pointer_loop() {
+    let mut msg = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    // Question name pointing at itself
+    msg.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+    assert_eq!(
+        Packet::new(false).deserialize(&msg),
+        Err(DnsParseError::PointerLoop(12))
+    );
+
+    let mut msg = vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    // Label followed by a pointer back to the start of the same name
+    msg.extend_from_slice(&[1, b'a', 0xC0, 12, 0, 1, 0, 1]);
+    assert_eq!(
+        Packet::new(false).deserialize(&msg),
+        Err(DnsParseError::PointerLoop(14))
+    );
+}
+
+#[test]
+fn check_malformed_packets() -> Result<(), Box<dyn error::Error>> {
+    let mut p = Packet {
+        questions: vec![Question {
+            qname: DnsName::from_str("abc.xyz.com")?,
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        }],
+        answers: vec![Record {
+            rname: DnsName::from_str("abc.xyz.com")?,
+            rtype: RecordType::A,
+            rclass: RecordClass::IN,
+            ttl: 256,
+            data_length: 4,
+            data: BitVec::from_slice(&[1, 2, 3, 4]),
+        }],
+        is_response: true,
+        ..Default::default()
+    };
+    let binding = p.serialize(1)?;
+    let buf = binding.as_raw_slice();
+
+    // Every truncation of a valid packet must fail without panicking
+    for len in 0..buf.len() {
+        assert!(Packet::new(true).deserialize(&buf[..len]).is_err());
+    }
+    assert_eq!(
+        Packet::new(true).deserialize(&buf[..5]),
+        Err(DnsParseError::TruncatedHeader(4))
+    );
+    assert_eq!(
+        Packet::new(true).deserialize(&buf[..15]),
+        Err(DnsParseError::TruncatedName(13))
+    );
+    assert_eq!(
+        Packet::new(true).deserialize(&buf[..43]),
+        Err(DnsParseError::TruncatedRdata(41))
+    );
+
+    // Types and classes we don't know are kept as they are
+    let mut other_type = buf.to_vec();
+    other_type[26] = 0xFF;
+    other_type[28] = 0x03;
+    let mut received = Packet::new(true);
+    received.deserialize(&other_type)?;
+    assert_eq!(received.questions[0].qtype, RecordType::Unknown(0xFF));
+    assert_eq!(received.questions[0].qclass, RecordClass::Unknown(3));
+    assert_eq!(
+        received.serialize(received.id())?.as_raw_slice(),
+        other_type
+    );
+
+    let mut bad_label = buf.to_vec();
+    bad_label[12] = 0b01000011;
+    assert_eq!(
+        Packet::new(true).deserialize(&bad_label),
+        Err(DnsParseError::BadLabel(12))
+    );
+
+    let mut query = Packet {
+        questions: vec![Question {
+            qname: DnsName::from_str("not-base32!.xyz.com")?,
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        }],
+        ..Default::default()
+    };
+    let domain = DnsName::from_str("xyz.com")?;
+    assert!(matches!(
+        query.extract_data(&domain),
+        Err(DnsParseError::UndecodableLabel(_))
+    ));
+    query.answers.push(Record {
+        data_length: 2,
+        data: BitVec::from_slice(&[1, 2, 3, 4]),
+        ..Record::new()
+    });
+    assert_eq!(
+        query.serialize(1),
+        Err(DnsParseError::DataExceedMaxLen(2, 4))
+    );
+    Ok(())
+}
+
+#[test]
+fn check_tunnel_domain() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let data = b"some tunnel data";
+    let mut query = Packet::new(false);
+    query.embed_data(data, None, &domain, &codec::Base32)?;
+    let binding = query.serialize(1)?;
+
+    let mut received = Packet::new(false);
+    received.deserialize(binding.as_raw_slice())?;
+    assert_eq!(received.extract_data(&domain)?, data);
+    // Suffix matching ignores case
+    let upper = DnsName::from_str("T.EXAMPLE.ORG")?;
+    assert_eq!(received.extract_data(&upper)?, data);
+    assert_eq!(
+        received.extract_data(&DnsName::from_str("example.com")?),
+        Err(DnsParseError::NameOutsideZone(
+            received.questions[0].qname.to_string()
+        ))
+    );
+
+    // Data goes in as many A answers as it takes, which may come in any order,
+    // and nowhere else
+    let mut reply = Packet::new(true);
+    reply.embed_data(&[7; 40], Some(&received), &domain, &codec::Base32)?;
+    assert!(reply.additional.is_empty());
+    assert_eq!(reply.answers.len(), 14);
+    reply.answers.reverse();
+    let extracted = reply.extract_data(&domain)?;
+    assert_eq!(extracted[..40], [7; 40]);
+    assert_eq!(extracted[40..], [0, 0]);
+    Ok(())
+}
+
+#[test]
+fn check_authoritative_answers() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let nameserver = DnsName::from_str("ns.example.org")?;
+    let mut query = Packet::new(false);
+    query.set_recursion_desired(true);
+    query.questions.push(Question {
+        qname: DnsName::from_str("T.eXample.ORG")?,
+        qtype: RecordType::SOA,
+        qclass: RecordClass::IN,
+    });
+    assert!(query.in_zone(&domain));
+    assert!(query.is_apex_query(&domain));
+
+    let mut reply = Packet::response_to(&query);
+    reply.answer_apex(&domain, &nameserver)?;
+    let binding = reply.serialize(0x1234)?;
+    let mut received = Packet::new(true);
+    received.deserialize(binding.as_raw_slice())?;
+    assert_eq!(received.id(), 0x1234);
+    assert!(received.authoritative && received.recursion_desired);
+    assert!(!received.is_truncated());
+    assert_eq!(received.rcode(), ResponseCode::NoError);
+    // Question is echoed with the case it was asked with
+    assert_eq!(received.questions, query.questions);
+    assert_eq!(received.answers[0].rtype, RecordType::SOA);
+
+    query.questions[0].qtype = RecordType::A;
+    let mut reply = Packet::response_to(&query);
+    reply.answer_apex(&domain, &nameserver)?;
+    assert!(reply.answers.is_empty());
+    assert_eq!(reply.authorities[0].rtype, RecordType::SOA);
+
+    query.questions[0].qname = DnsName::from_str("www.example.org")?;
+    assert!(!query.in_zone(&domain));
+    let mut reply = Packet::response_to(&query);
+    reply.set_rcode(ResponseCode::Refused);
+    reply.set_truncated(true);
+    let binding = reply.serialize(1)?;
+    assert_eq!(binding.as_raw_slice()[2..4], [0x87, 0x05]);
+    let mut received = Packet::new(true);
+    received.deserialize(binding.as_raw_slice())?;
+    assert_eq!(received.rcode(), ResponseCode::Refused);
+    assert!(received.is_truncated());
+    Ok(())
+}
+
+#[test]
+fn check_capacity() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    // Distinct bytes, so that no whole names get compressed
+    let data: Vec<u8> = (0..=255).cycle().take(1024).collect();
+    for codec in codec::CODECS {
+        for max_len in [100, 300, 512] {
+            // One question, with a name as long as the response or the name
+            // length limit allows
+            let capacity = Packet::query_capacity(&domain, RecordType::A, codec, max_len);
+            let mut query = Packet::new(false);
+            query.embed_data(&data[..capacity], None, &domain, codec)?;
+            assert_eq!(query.questions.len(), 1);
+            let name_len = query.questions[0].qname.wire_len();
+            let len = query.serialize(1)?.len() / 8 + 16;
+            assert!(len <= max_len && name_len <= MAX_NAME_LEN, "{:?}", codec);
+            assert!(
+                len + 4 > max_len || name_len + 4 > MAX_NAME_LEN,
+                "{:?}",
+                codec
+            );
+            assert!(query.response_capacity(&domain, codec, max_len) >= 3);
+            assert!(Packet::new(false)
+                .embed_data(&data[..capacity + 200], None, &domain, codec)
+                .is_err());
+            // The longest name, with the largest data values
+            let longest = data_name_capacity(MAX_NAME_LEN - domain.wire_len(), codec);
+            let mut request = Packet::new(false);
+            request.embed_data(&vec![0xff; longest], None, &domain, codec)?;
+            let mut received = Packet::new(false);
+            received.deserialize(request.serialize(1)?.as_raw_slice())?;
+            assert_eq!(received.extract_data(&domain)?, vec![0xff; longest]);
+
+            for qtype in [RecordType::A, RecordType::CNAME] {
+                query.set_query_type(qtype);
+                let capacity = query.response_capacity(&domain, codec, max_len * 2);
+                let mut reply = Packet::new(true);
+                reply.embed_data(&data[..capacity], Some(&query), &domain, codec)?;
+                let binding = reply.serialize(1)?;
+                let len = binding.len() / 8;
+                assert!(len <= max_len * 2, "{:?}", codec);
+                // A answers fill the response, a CNAME answer has one name
+                if qtype == RecordType::A {
+                    assert!(len + 16 > max_len * 2, "{:?}", codec);
+                }
+
+                let mut received = Packet::new(true);
+                received.deserialize(binding.as_raw_slice())?;
+                assert_eq!(received.extract_data(&domain)?, data[..capacity]);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn check_edns() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let mut query = Packet::new(false);
+    query.embed_data(b"query", None, &domain, &codec::Base32)?;
+    assert_eq!(query.max_response_len(), MAX_UDP_LEN);
+    let mut edns = Edns::new(4000);
+    edns.dnssec_ok = true;
+    edns.options.push(EdnsOption {
+        code: 10,
+        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+    });
+    query.set_edns(Some(edns.clone()));
+    let binding = query.serialize(1)?;
+    let mut received = Packet::new(false);
+    received.deserialize(binding.as_raw_slice())?;
+    assert_eq!(received.edns(), Some(&edns));
+    assert!(received.additional.is_empty());
+    // Sizes we don't go to are cut down
+    assert_eq!(received.max_response_len(), MAX_EDNS_UDP_LEN);
+
+    // Responses advertise our own size, and carry the upper bits of extended
+    // response codes in the OPT record
+    let mut reply = Packet::response_to(&received);
+    reply.set_rcode(ResponseCode::BadVersion);
+    let buf = reply.serialize(1)?.into_vec();
+    assert_eq!(buf[3] & 0x0F, 0);
+    let mut received = Packet::new(true);
+    received.deserialize(&buf)?;
+    assert_eq!(received.rcode(), ResponseCode::BadVersion);
+    assert_eq!(
+        received.edns().map(|edns| edns.udp_payload_size as usize),
+        Some(MAX_EDNS_UDP_LEN)
+    );
+    assert_eq!(
+        buf.len(),
+        12 + query.questions[0].qname.wire_len() + 4 + OPT_LEN
+    );
+
+    // One OPT record at most, owned by the root, with whole options
+    let len = buf.len();
+    let mut twice = buf.clone();
+    twice[11] = 2;
+    twice.extend_from_slice(&buf[len - OPT_LEN..]);
+    assert_eq!(
+        Packet::new(true).deserialize(&twice),
+        Err(DnsParseError::BadOpt(len))
+    );
+    let mut option = buf.clone();
+    option[len - 1] = 3;
+    option.extend_from_slice(&[0, 10, 0]);
+    assert_eq!(
+        Packet::new(true).deserialize(&option),
+        Err(DnsParseError::BadOpt(len - OPT_LEN))
+    );
+    Ok(())
+}
+
+#[test]
+fn check_edns_capacity() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+    for qtype in [
+        RecordType::A,
+        RecordType::AAAA,
+        RecordType::TXT,
+        RecordType::NULL,
+        RecordType::CNAME,
+        RecordType::MX,
+        RecordType::SRV,
+    ] {
+        let mut query = Packet::new(false);
+        query.embed_data(b"query", None, &domain, &codec::Base32)?;
+        query.set_query_type(qtype);
+        query.set_edns(Some(Edns::new(MAX_EDNS_UDP_LEN as u16)));
+        let max_len = query.max_response_len();
+        let capacity = query.response_capacity(&domain, &codec::Base32, max_len);
+        let mut reply = Packet::response_to(&query);
+        reply.embed_data(&data[..capacity], Some(&query), &domain, &codec::Base32)?;
+        let binding = reply.serialize(1)?;
+        assert!(binding.len() / 8 <= max_len, "{}", qtype);
+
+        // Resolvers only pass on the answers and the OPT record, which hold
+        // all of the data
+        let mut received = Packet::new(true);
+        received.deserialize(binding.as_raw_slice())?;
+        assert!(received.additional.is_empty(), "{}", qtype);
+        assert!(received.edns().is_some());
+        let extracted = received.extract_data(&domain)?;
+        assert_eq!(extracted[..capacity], data[..capacity], "{}", qtype);
+        // Addresses fill the response, one more byte takes one too many
+        if matches!(qtype, RecordType::A | RecordType::AAAA) {
+            let mut more = Packet::response_to(&query);
+            more.embed_data(&data[..capacity + 1], Some(&query), &domain, &codec::Base32)?;
+            assert!(more.serialize(1)?.len() / 8 > max_len, "{}", qtype);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn check_txt_records() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let data: Vec<u8> = (0..=255).cycle().take(2048).collect();
+    let mut query = Packet::new(false);
+    query.embed_data(b"query", None, &domain, &codec::Base32)?;
+    query.set_query_type(RecordType::TXT);
+
+    // Long data is split over several strings, and the response fills up
+    // to the byte
+    for max_len in [100, 400, 512, 1200] {
+        let capacity = query.response_capacity(&domain, &codec::Base32, max_len);
+        let mut reply = Packet::new(true);
+        reply.embed_data(&data[..capacity], Some(&query), &domain, &codec::Base32)?;
+        assert!(reply.additional.is_empty());
+        let binding = reply.serialize(1)?;
+        assert_eq!(binding.len() / 8, max_len);
+
+        let mut received = Packet::new(true);
+        received.deserialize(binding.as_raw_slice())?;
+        assert_eq!(received.answers[0].rtype, RecordType::TXT);
+        assert_eq!(received.extract_data(&domain)?, data[..capacity]);
+    }
+
+    // Empty answers still hold one string
+    let mut reply = Packet::new(true);
+    reply.embed_data(&[], Some(&query), &domain, &codec::Base32)?;
+    assert_eq!(reply.answers[0].data.as_raw_slice(), [0]);
+    assert!(reply.extract_data(&domain)?.is_empty());
+
+    // Strings running past the end of the rdata are rejected
+    let mut reply = Packet::new(true);
+    reply.embed_data(&[1, 2, 3], Some(&query), &domain, &codec::Base32)?;
+    let mut buf = reply.serialize(1)?.into_vec();
+    let len = buf.len();
+    buf[len - 4] = 4;
+    assert_eq!(
+        Packet::new(true).deserialize(&buf),
+        Err(DnsParseError::TruncatedRdata(len - 4))
+    );
+    Ok(())
+}
+
+#[test]
+fn check_record_carriers() -> Result<(), Box<dyn error::Error>> {
+    let domain = DnsName::from_str("t.example.org")?;
+    let data: Vec<u8> = (0..=255).cycle().take(2048).collect();
+    for qtype in [
+        RecordType::CNAME,
+        RecordType::NULL,
+        RecordType::MX,
+        RecordType::SRV,
+    ] {
+        // Queries leave room for the answers to their questions
+        let capacity = Packet::query_capacity(&domain, qtype, &codec::Base32, 512);
+        let mut query = Packet::new(false);
+        query.embed_data(&data[..capacity], None, &domain, &codec::Base32)?;
+        query.set_query_type(qtype);
+        let len = query.serialize(1)?.len() / 8;
+        let base = len + query.questions.len() * answer_len(qtype, &domain).unwrap();
+        assert!(base <= 512);
+
+        for max_len in [base, base + 30, 512, 1200] {
+            let capacity = query.response_capacity(&domain, &codec::Base32, max_len);
+            let mut reply = Packet::new(true);
+            reply.embed_data(&data[..capacity], Some(&query), &domain, &codec::Base32)?;
+            let binding = reply.serialize(1)?;
+            assert!(binding.len() / 8 <= max_len, "{:?} {}", qtype, max_len);
+
+            let mut received = Packet::new(true);
+            received.deserialize(binding.as_raw_slice())?;
+            assert!(received.answers.iter().all(|a| a.rtype == qtype));
+            // Resolvers may hand out the records of an RRset in any order
+            if matches!(qtype, RecordType::MX | RecordType::SRV) {
+                received.answers.reverse();
+            }
+            assert_eq!(received.extract_data(&domain)?, data[..capacity]);
+        }
+    }
+
+    // Names in rdata may be compressed by whoever sent the response
+    let mut msg = vec![0, 1, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+    msg.extend_from_slice(b"\x01a\x01t\x07example\x03org\x00\x00\x0f\x00\x01");
+    msg.extend_from_slice(&[0xC0, 12, 0, 15, 0, 1, 0, 0, 0, 0, 0, 8, 0, 7]);
+    msg.extend_from_slice(&[3, b'b', b'a', b'c', 0xC0, 14]);
+    let mut received = Packet::new(true);
+    received.deserialize(&msg)?;
+    assert_eq!(
+        received.answers[0].rdata()?,
+        Rdata::Mx {
+            preference: 7,
+            exchange: DnsName::from_str("bac.t.example.org")?,
+        }
+    );
+    assert_eq!(received.extract_data(&domain)?, [0x53]);
+    // Rdata must hold the name and nothing more
+    msg[44] = 9;
+    msg.push(0);
+    assert_eq!(
+        Packet::new(true).deserialize(&msg),
+        Err(DnsParseError::BadRdata(45))
+    );
+    Ok(())
+}
@@ -0,0 +1,440 @@
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+use data_encoding::BASE64URL_NOPAD;
+
+use crate::tcp::MAX_TCP_LEN;
+
+// DNS over HTTPS (RFC 8484). Queries go in the body of POST requests, or in
+// the `dns` parameter of GET requests as base64url without padding, and
+// responses in the body, all of type application/dns-message. Only HTTP/1.1
+// is spoken, over whatever stream the caller set up, TLS being up to them.
+
+pub const CONTENT_TYPE: &str = "application/dns-message";
+pub const DEFAULT_PATH: &str = "/dns-query";
+// Protocol asked for during the TLS handshake
+pub const ALPN: &[u8] = b"http/1.1";
+const DEFAULT_PORT: u16 = 443;
+// Limits on what we take from the other side
+const MAX_LINE_LEN: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+#[derive(Debug)]
+pub enum DohError {
+    Io(io::Error),
+    // Not a valid HTTP message
+    Malformed(&'static str),
+    // Status of a failed request. The server answers with it, the client got
+    // it from the server.
+    Status(u16),
+}
+
+impl fmt::Display for DohError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DohError::Io(e) => write!(f, "{}", e),
+            DohError::Malformed(what) => write!(f, "Malformed HTTP message: {}", what),
+            DohError::Status(status) => write!(f, "HTTP status {}", status),
+        }
+    }
+}
+
+impl error::Error for DohError {}
+
+impl From<io::Error> for DohError {
+    fn from(e: io::Error) -> Self {
+        DohError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Method::Get),
+            "POST" => Ok(Method::Post),
+            _ => Err(format!("unknown method {}", s)),
+        }
+    }
+}
+
+// Where queries are sent, https://host[:port][/path]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DohUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for DohUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected https://HOST[:PORT][/PATH], got {}", s);
+        let rest = s.strip_prefix("https://").ok_or_else(error)?;
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, DEFAULT_PATH),
+        };
+        // IPv6 addresses go in brackets
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(error)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(error());
+        }
+        Ok(DohUrl {
+            host: host.to_string(),
+            port: match port {
+                Some(port) => port.parse().map_err(|_| error())?,
+                None => DEFAULT_PORT,
+            },
+            path: path.to_string(),
+        })
+    }
+}
+
+impl DohUrl {
+    // Host as it goes in the Host header
+    fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match self.port {
+            DEFAULT_PORT => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+pub fn write_query<W: Write>(
+    stream: &mut W,
+    url: &DohUrl,
+    method: Method,
+    query: &[u8],
+) -> io::Result<()> {
+    let mut request = match method {
+        Method::Get => format!(
+            "GET {}?dns={} HTTP/1.1\r\n",
+            url.path,
+            BASE64URL_NOPAD.encode(query)
+        ),
+        Method::Post => format!(
+            "POST {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            url.path,
+            CONTENT_TYPE,
+            query.len()
+        ),
+    };
+    request.push_str(&format!(
+        "Host: {}\r\nAccept: {}\r\n\r\n",
+        url.authority(),
+        CONTENT_TYPE
+    ));
+    let mut buf = request.into_bytes();
+    if method == Method::Post {
+        buf.extend_from_slice(query);
+    }
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+// Body of the response to the last query
+pub fn read_response<R: BufRead>(stream: &mut R) -> Result<Vec<u8>, DohError> {
+    let head = read_head(stream)?.ok_or(DohError::Io(io::ErrorKind::UnexpectedEof.into()))?;
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return Err(DohError::Malformed("bad response head")),
+    }
+    let body = read_body(stream, response.headers)?;
+    match response.code {
+        Some(200) => (),
+        Some(status) => return Err(DohError::Status(status)),
+        None => return Err(DohError::Malformed("no status")),
+    }
+    if !is_dns_message(response.headers) {
+        return Err(DohError::Malformed("not a DNS message"));
+    }
+    Ok(body)
+}
+
+// Query carried by the next request to `path`, or None once the client
+// closed the connection
+pub fn read_query<R: BufRead>(stream: &mut R, path: &str) -> Result<Option<Vec<u8>>, DohError> {
+    let Some(head) = read_head(stream)? else {
+        return Ok(None);
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return Err(DohError::Status(400)),
+    }
+    let body = read_body(stream, request.headers)?;
+    let (target, params) = match request.path {
+        Some(target) => target.split_once('?').unwrap_or((target, "")),
+        None => return Err(DohError::Status(400)),
+    };
+    if target != path {
+        return Err(DohError::Status(404));
+    }
+    let query = match request.method {
+        Some("GET") => {
+            let dns = params
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(DohError::Status(400))?;
+            BASE64URL_NOPAD
+                .decode(dns.as_bytes())
+                .map_err(|_| DohError::Status(400))?
+        }
+        Some("POST") => {
+            if !is_dns_message(request.headers) {
+                return Err(DohError::Status(415));
+            }
+            body
+        }
+        _ => return Err(DohError::Status(405)),
+    };
+    Ok(Some(query))
+}
+
+pub fn write_response<W: Write>(stream: &mut W, response: &[u8]) -> io::Result<()> {
+    let mut buf = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\r\n",
+        CONTENT_TYPE,
+        response.len()
+    )
+    .into_bytes();
+    buf.extend_from_slice(response);
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+// Answer a request which failed, after which the connection is closed
+pub fn write_error<W: Write>(stream: &mut W, status: u16) -> io::Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    )?;
+    stream.flush()
+}
+
+fn is_dns_message(headers: &[httparse::Header]) -> bool {
+    header(headers, "Content-Type").is_some_and(|value| {
+        value
+            .split(';')
+            .next()
+            .is_some_and(|media| media.trim().eq_ignore_ascii_case(CONTENT_TYPE))
+    })
+}
+
+fn header<'a>(headers: &'a [httparse::Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+}
+
+fn read_line<R: BufRead>(stream: &mut R) -> Result<Vec<u8>, DohError> {
+    let mut line = Vec::new();
+    stream
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return match line.len() {
+            MAX_LINE_LEN => Err(DohError::Malformed("line too long")),
+            _ => Err(DohError::Io(io::ErrorKind::UnexpectedEof.into())),
+        };
+    }
+    Ok(line)
+}
+
+// Start line and headers up to the empty line after them, or None if the
+// stream ended before the message. Plenty of TLS peers close connections
+// without notifying, which is fine between messages.
+fn read_head<R: BufRead>(stream: &mut R) -> Result<Option<Vec<u8>>, DohError> {
+    let mut head = Vec::new();
+    loop {
+        if head.is_empty() {
+            match stream.fill_buf() {
+                Ok([]) => return Ok(None),
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let line = read_line(stream)?;
+        head.extend_from_slice(&line);
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(Some(head));
+        }
+        if head.len() > MAX_LINE_LEN * 4 {
+            return Err(DohError::Malformed("head too long"));
+        }
+    }
+}
+
+// Body following the head with `headers`, with its length given or in chunks
+fn read_body<R: BufRead>(
+    stream: &mut R,
+    headers: &[httparse::Header],
+) -> Result<Vec<u8>, DohError> {
+    if header(headers, "Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        let mut body = Vec::new();
+        loop {
+            let line = read_line(stream)?;
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| line.split(';').next())
+                .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                .ok_or(DohError::Malformed("bad chunk size"))?;
+            if size == 0 {
+                // Trailers up to the empty line
+                while !matches!(&read_line(stream)?[..], b"\r\n" | b"\n") {}
+                return Ok(body);
+            }
+            if body.len() + size > MAX_TCP_LEN {
+                return Err(DohError::Status(413));
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            stream.read_exact(&mut body[start..])?;
+            read_line(stream)?;
+        }
+    }
+    let len = match header(headers, "Content-Length") {
+        Some(len) => len
+            .trim()
+            .parse()
+            .map_err(|_| DohError::Malformed("bad content length"))?,
+        None => 0,
+    };
+    if len > MAX_TCP_LEN {
+        return Err(DohError::Status(413));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// Tests
+
+#[test]
+fn check_url() {
+    let url: DohUrl = "https://dns.example.net/dns-query".parse().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("dns.example.net", 443, "/dns-query")
+    );
+    assert_eq!(url.authority(), "dns.example.net");
+    let url: DohUrl = "https://[::1]:8443".parse().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("::1", 8443, DEFAULT_PATH)
+    );
+    assert_eq!(url.authority(), "[::1]:8443");
+    assert!("http://dns.example.net/".parse::<DohUrl>().is_err());
+    assert!("https://:443/".parse::<DohUrl>().is_err());
+    assert!("https://host:port/".parse::<DohUrl>().is_err());
+}
+
+#[test]
+fn check_exchange() -> Result<(), Box<dyn error::Error>> {
+    let url: DohUrl = "https://127.0.0.1:8443/dns-query".parse()?;
+    let query: Vec<u8> = (0..=255).collect();
+
+    // Requests of both kinds back to back on a connection, as a client
+    // sends them
+    let mut requests = Vec::new();
+    write_query(&mut requests, &url, Method::Get, &query)?;
+    write_query(&mut requests, &url, Method::Post, &query)?;
+    assert!(requests.starts_with(b"GET /dns-query?dns=AAECAwQF"));
+    let mut stream = io::Cursor::new(requests);
+    for _ in 0..2 {
+        assert_eq!(read_query(&mut stream, "/dns-query")?, Some(query.clone()));
+    }
+    assert_eq!(read_query(&mut stream, "/dns-query")?, None);
+
+    let mut responses = Vec::new();
+    write_response(&mut responses, &query)?;
+    write_response(&mut responses, b"")?;
+    let mut stream = io::Cursor::new(responses);
+    assert_eq!(read_response(&mut stream)?, query);
+    assert_eq!(read_response(&mut stream)?, b"");
+
+    // Resolvers may send responses in chunks
+    let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+        Content-Type: application/dns-message\r\n\r\n\
+        3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n";
+    assert_eq!(read_response(&mut &chunked[..])?, b"abcde");
+    Ok(())
+}
+
+#[test]
+fn check_errors() -> Result<(), Box<dyn error::Error>> {
+    let status = |request: &[u8]| match read_query(&mut &request[..], DEFAULT_PATH) {
+        Err(DohError::Status(status)) => status,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(status(b"GET /other?dns=AA HTTP/1.1\r\n\r\n"), 404);
+    assert_eq!(status(b"GET /dns-query?x=AA HTTP/1.1\r\n\r\n"), 400);
+    assert_eq!(status(b"GET /dns-query?dns=!! HTTP/1.1\r\n\r\n"), 400);
+    assert_eq!(status(b"PUT /dns-query HTTP/1.1\r\n\r\n"), 405);
+    assert_eq!(
+        status(b"POST /dns-query HTTP/1.1\r\nContent-Length: 2\r\n\r\nAA"),
+        415
+    );
+    assert_eq!(
+        status(b"POST /dns-query HTTP/1.1\r\nContent-Length: 99999\r\n\r\n"),
+        413
+    );
+    assert_eq!(status(b"garbage\r\n\r\n"), 400);
+    // Requests cut off are an error, not the end of the connection
+    assert!(read_query(
+        &mut &b"GET /dns-query?dns=AA HTTP/1.1\r\n"[..],
+        DEFAULT_PATH
+    )
+    .is_err());
+
+    let mut not_found = Vec::new();
+    write_error(&mut not_found, 404)?;
+    assert!(matches!(
+        read_response(&mut &not_found[..]),
+        Err(DohError::Status(404))
+    ));
+    let html = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 0\r\n\r\n";
+    assert!(matches!(
+        read_response(&mut &html[..]),
+        Err(DohError::Malformed(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use chacha20poly1305::aead::OsRng;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::keys::{Key, KEY_LEN};
+
+// Sessions get keys of their own, agreed on with an X25519 exchange of
+// ephemeral keys in the request for the session and its response, much like
+// Noise's NNpsk0 pattern. Both messages are encrypted with the pre-shared key,
+// so only its holders can take part, and the session keys are derived from
+// the exchange and the pre-shared key together. Once both sides have dropped
+// their ephemeral secrets, recorded sessions can't be decrypted even by
+// someone who gets hold of the pre-shared key.
+
+pub const PUBLIC_LEN: usize = 32;
+// Binds the keys to what they're for, and to the public keys exchanged
+const INFO: &[u8] = b"dns-camo session v1";
+
+// Keys for data going upstream, from client to server, and downstream
+#[derive(Debug, Clone)]
+pub struct SessionKeys {
+    pub upstream: Key,
+    pub downstream: Key,
+}
+
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Handshake { secret, public }
+    }
+
+    // Ephemeral public key to send the other side
+    pub fn public(&self) -> [u8; PUBLIC_LEN] {
+        self.public.to_bytes()
+    }
+
+    // Keys shared with the side whose ephemeral public key is `peer`. Public
+    // keys which leave nothing secret are refused.
+    pub fn finish(self, peer: &[u8; PUBLIC_LEN], psk: &Key, client: bool) -> Option<SessionKeys> {
+        let ours = self.public();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let (client, server) = if client { (ours, *peer) } else { (*peer, ours) };
+        let info = [INFO, &client, &server].concat();
+        let mut keys = [0u8; 2 * KEY_LEN];
+        Hkdf::<Sha256>::new(Some(psk.as_bytes()), shared.as_bytes())
+            .expand(&info, &mut keys)
+            .ok()?;
+        let (upstream, downstream) = keys.split_at(KEY_LEN);
+        Some(SessionKeys {
+            upstream: Key::new(upstream.try_into().ok()?),
+            downstream: Key::new(downstream.try_into().ok()?),
+        })
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Tests
+
+#[test]
+fn check_handshake() {
+    let psk = Key::new([0x42; KEY_LEN]);
+    let (client, server) = (Handshake::new(), Handshake::new());
+    let (client_public, server_public) = (client.public(), server.public());
+    let ours = client.finish(&server_public, &psk, true).unwrap();
+    let theirs = server.finish(&client_public, &psk, false).unwrap();
+    assert_eq!(ours.upstream, theirs.upstream);
+    assert_eq!(ours.downstream, theirs.downstream);
+    assert_ne!(ours.upstream, ours.downstream);
+    assert_ne!(ours.upstream, psk);
+
+    // Every session gets fresh keys, which depend on the pre-shared key
+    let (client, server) = (Handshake::new(), Handshake::new());
+    let other = client.finish(&server.public(), &psk, true).unwrap();
+    assert_ne!(other.upstream, ours.upstream);
+    let (client, server) = (Handshake::new(), Handshake::new());
+    let client_public = client.public();
+    let ours = client
+        .finish(&server.public(), &Key::new([0x43; KEY_LEN]), true)
+        .unwrap();
+    let theirs = server.finish(&client_public, &psk, false).unwrap();
+    assert_ne!(ours.upstream, theirs.upstream);
+
+    // Low-order points would make the shared secret known to anyone
+    assert!(Handshake::new()
+        .finish(&[0; PUBLIC_LEN], &psk, true)
+        .is_none());
+}
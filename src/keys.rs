@@ -0,0 +1,454 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use data_encoding::BASE64;
+
+// Pre-shared keys. A client has a single key, the server a keyring with the
+// key of every client it serves, each under the identity it's known by.
+//
+// Key files hold either the 32 bytes of the key and nothing else, or a header
+// line and the key in base64:
+//
+//     dns-camo key v1
+//     3q2+7wAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+//
+// or, for keys derived from a passphrase with Argon2id, the parameters and
+// the salt to derive it with:
+//
+//     dns-camo passphrase v1 argon2id m=19456 t=2 p=1
+//     c2FsdHNhbHRzYWx0c2FsdA==
+//
+// Anything else is refused rather than taken for some key. Keyrings are text
+// files with a line for each client, its identity and its key in base64:
+//
+//     # identity  key
+//     alice       3q2+7wAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+//     bob         yv66vgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+
+pub const KEY_LEN: usize = 32;
+// Environment variable the binaries take the passphrase from
+pub const PASSPHRASE_VAR: &str = "DNS_CAMO_PASSPHRASE";
+const SALT_LEN: usize = 16;
+// Raw keys starting like this would be taken for text, which is as likely as
+// guessing 9 bytes of a random key
+const TEXT_PREFIX: &str = "dns-camo ";
+const KEY_HEADER: &str = "dns-camo key v1";
+const PASSPHRASE_HEADER: &str = "dns-camo passphrase v1";
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(PathBuf, io::Error),
+    // Key file which doesn't hold exactly a key, in any format
+    Length(PathBuf, usize),
+    Invalid(PathBuf, &'static str),
+    // Key to derive from a passphrase which wasn't given
+    NoPassphrase(PathBuf),
+    Argon2(argon2::Error),
+    // Keyring line which isn't an identity and a key
+    Malformed(PathBuf, usize),
+    Duplicate(PathBuf, String),
+    Empty(PathBuf),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            KeyError::Length(path, len) => write!(
+                f,
+                "{} holds {} bytes, keys are {} bytes",
+                path.display(),
+                len,
+                KEY_LEN
+            ),
+            KeyError::Invalid(path, reason) => write!(f, "{}: {}", path.display(), reason),
+            KeyError::NoPassphrase(path) => write!(
+                f,
+                "{} derives the key from a passphrase, set {}",
+                path.display(),
+                PASSPHRASE_VAR
+            ),
+            KeyError::Argon2(e) => write!(f, "Key derivation failed: {}", e),
+            KeyError::Malformed(path, line) => write!(
+                f,
+                "{}:{}: expected an identity and a base64 key",
+                path.display(),
+                line
+            ),
+            KeyError::Duplicate(path, identity) => {
+                write!(f, "{}: {} is listed twice", path.display(), identity)
+            }
+            KeyError::Empty(path) => write!(f, "{} holds no keys", path.display()),
+        }
+    }
+}
+
+impl error::Error for KeyError {}
+
+// How keys are written to key files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    Raw,
+    Base64,
+}
+
+impl FromStr for KeyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" => Ok(KeyFormat::Raw),
+            "base64" => Ok(KeyFormat::Base64),
+            _ => Err(format!("unknown key format {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Key(key)
+    }
+
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        Key(key)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, KeyError> {
+        Key::load(path, None)
+    }
+
+    // Key in a key file of any format, those deriving it from a passphrase
+    // needing `passphrase`
+    pub fn load(path: &Path, passphrase: Option<&str>) -> Result<Self, KeyError> {
+        let buf = fs::read(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+        if !buf.starts_with(TEXT_PREFIX.as_bytes()) {
+            let len = buf.len();
+            return buf
+                .try_into()
+                .map(Key)
+                .map_err(|_| KeyError::Length(path.to_path_buf(), len));
+        }
+        let invalid = |reason| KeyError::Invalid(path.to_path_buf(), reason);
+        let text = std::str::from_utf8(&buf).map_err(|_| invalid("not a key file"))?;
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let (header, body) = text
+            .split_once('\n')
+            .filter(|(_, body)| !body.contains('\n'))
+            .ok_or_else(|| invalid("expected a header line and a base64 line"))?;
+        if header == KEY_HEADER {
+            BASE64
+                .decode(body.as_bytes())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .map(Key)
+                .ok_or_else(|| invalid("expected a 32-byte key in base64"))
+        } else if let Some(params) = header
+            .strip_prefix(PASSPHRASE_HEADER)
+            .and_then(|params| params.strip_prefix(' '))
+        {
+            let derivation = KeyDerivation::parse(params, body)
+                .ok_or_else(|| invalid("expected Argon2id parameters and a base64 salt"))?;
+            let passphrase = passphrase
+                .filter(|passphrase| !passphrase.is_empty())
+                .ok_or_else(|| KeyError::NoPassphrase(path.to_path_buf()))?;
+            derivation.derive(passphrase)
+        } else {
+            Err(invalid("unknown key file format"))
+        }
+    }
+
+    pub fn encode(&self, format: KeyFormat) -> Vec<u8> {
+        match format {
+            KeyFormat::Raw => self.0.to_vec(),
+            KeyFormat::Base64 => {
+                format!("{}\n{}\n", KEY_HEADER, BASE64.encode(&self.0)).into_bytes()
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+// Keys stay out of logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+// Salt and Argon2id parameters to derive a key from a passphrase with. Only
+// these go in the key file, the key is derived again every time it's loaded.
+#[derive(Debug, Clone)]
+pub struct KeyDerivation {
+    salt: [u8; SALT_LEN],
+    params: Params,
+}
+
+impl KeyDerivation {
+    // Fresh salt, with the parameters recommended by the argon2 crate
+    pub fn generate() -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        KeyDerivation {
+            salt,
+            params: Params::DEFAULT,
+        }
+    }
+
+    fn parse(params: &str, salt: &str) -> Option<Self> {
+        let mut fields = params.split(' ');
+        if fields.next()? != "argon2id" {
+            return None;
+        }
+        let mut value = |name| fields.next()?.strip_prefix(name)?.parse::<u32>().ok();
+        let (m, t, p) = (value("m=")?, value("t=")?, value("p=")?);
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(KeyDerivation {
+            salt: BASE64.decode(salt.as_bytes()).ok()?.try_into().ok()?,
+            params: Params::new(m, t, p, Some(KEY_LEN)).ok()?,
+        })
+    }
+
+    pub fn derive(&self, passphrase: &str) -> Result<Key, KeyError> {
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(KeyError::Argon2)?;
+        Ok(Key(key))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        format!(
+            "{} argon2id m={} t={} p={}\n{}\n",
+            PASSPHRASE_HEADER,
+            self.params.m_cost(),
+            self.params.t_cost(),
+            self.params.p_cost(),
+            BASE64.encode(&self.salt)
+        )
+        .into_bytes()
+    }
+}
+
+// Write a new key file, which only its owner may read. Existing files are
+// left alone.
+pub fn write_key_file(path: &Path, contents: &[u8]) -> Result<(), KeyError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|e| KeyError::Io(path.to_path_buf(), e))
+}
+
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    clients: Vec<(String, Key)>,
+}
+
+impl Keyring {
+    // Keyring of a server all of whose clients share the one key
+    pub fn single(key: Key) -> Self {
+        Keyring {
+            clients: vec![(String::from("client"), key)],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, KeyError> {
+        let text = fs::read_to_string(path).map_err(|e| KeyError::Io(path.to_path_buf(), e))?;
+        let mut clients: Vec<(String, Key)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let (identity, key) = match (fields.next(), fields.next(), fields.next()) {
+                (None, _, _) => continue,
+                (Some(identity), Some(key), None) => (identity, key),
+                _ => return Err(KeyError::Malformed(path.to_path_buf(), number + 1)),
+            };
+            let key = BASE64
+                .decode(key.as_bytes())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .map(Key)
+                .ok_or_else(|| KeyError::Malformed(path.to_path_buf(), number + 1))?;
+            if clients.iter().any(|(other, _)| other == identity) {
+                return Err(KeyError::Duplicate(
+                    path.to_path_buf(),
+                    identity.to_string(),
+                ));
+            }
+            clients.push((identity.to_string(), key));
+        }
+        if clients.is_empty() {
+            return Err(KeyError::Empty(path.to_path_buf()));
+        }
+        Ok(Keyring { clients })
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    // Clients are referred to by their index in the keyring
+    pub fn identity(&self, client: usize) -> &str {
+        &self.clients[client].0
+    }
+
+    pub fn key(&self, client: usize) -> &Key {
+        &self.clients[client].1
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dns-camo-{}-{}", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn check_key_file() -> Result<(), Box<dyn error::Error>> {
+    let path = temp_file("key-file", &[0x42u8; KEY_LEN]);
+    assert_eq!(Key::from_file(&path)?, Key::new([0x42u8; KEY_LEN]));
+    // Short and long files aren't keys
+    for len in [0, KEY_LEN - 1, KEY_LEN + 1] {
+        fs::write(&path, vec![0x42u8; len])?;
+        assert!(matches!(Key::from_file(&path), Err(KeyError::Length(_, l)) if l == len));
+    }
+    fs::remove_file(&path)?;
+    assert!(matches!(Key::from_file(&path), Err(KeyError::Io(..))));
+
+    // Keys written in either format read back the same, once only
+    let key = Key::generate();
+    for format in [KeyFormat::Raw, KeyFormat::Base64] {
+        write_key_file(&path, &key.encode(format))?;
+        assert_eq!(Key::from_file(&path)?, key);
+        assert!(matches!(
+            write_key_file(&path, &key.encode(format)),
+            Err(KeyError::Io(..))
+        ));
+        fs::remove_file(&path)?;
+    }
+    let encoded = String::from_utf8(key.encode(KeyFormat::Base64))?;
+    let short = format!("{}\n{}\n", KEY_HEADER, BASE64.encode(&[1; KEY_LEN - 1]));
+    for text in [
+        encoded.trim_end().to_string(),
+        encoded.replace('\n', "\r\n"),
+        format!("{}extra\n", encoded),
+        encoded.replace("v1", "v2"),
+        short,
+        String::from("dns-camo key v1"),
+    ] {
+        fs::write(&path, &text)?;
+        let loaded = Key::from_file(&path);
+        assert_eq!(loaded.is_ok(), text == encoded.trim_end(), "{:?}", text);
+    }
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn check_passphrase() -> Result<(), Box<dyn error::Error>> {
+    let path = temp_file("passphrase", b"");
+    fs::remove_file(&path)?;
+    // Cheap parameters, for the test to run fast
+    let derivation = KeyDerivation {
+        salt: [7; SALT_LEN],
+        params: Params::new(64, 1, 1, Some(KEY_LEN)).unwrap(),
+    };
+    write_key_file(&path, &derivation.encode())?;
+    let key = Key::load(&path, Some("correct horse"))?;
+    assert_eq!(key, derivation.derive("correct horse")?);
+    assert_ne!(key, Key::load(&path, Some("battery staple"))?);
+    assert!(matches!(
+        Key::from_file(&path),
+        Err(KeyError::NoPassphrase(_))
+    ));
+    assert!(matches!(
+        Key::load(&path, Some("")),
+        Err(KeyError::NoPassphrase(_))
+    ));
+    // Other salts give other keys
+    let other = KeyDerivation {
+        salt: [8; SALT_LEN],
+        ..derivation.clone()
+    };
+    assert_ne!(other.derive("correct horse")?, key);
+
+    let salt = BASE64.encode(&[7; SALT_LEN]);
+    for params in [
+        "argon2i m=64 t=1 p=1",
+        "argon2id m=64 t=1",
+        "argon2id m=1 t=1 p=1",
+    ] {
+        fs::write(
+            &path,
+            format!("{} {}\n{}\n", PASSPHRASE_HEADER, params, salt),
+        )?;
+        assert!(matches!(
+            Key::load(&path, Some("correct horse")),
+            Err(KeyError::Invalid(..))
+        ));
+    }
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn check_keyring() -> Result<(), Box<dyn error::Error>> {
+    let alice = BASE64.encode(&[1u8; KEY_LEN]);
+    let bob = BASE64.encode(&[2u8; KEY_LEN]);
+    let text = format!("# clients\nalice {}\n\n  bob\t{}  # laptop\n", alice, bob);
+    let path = temp_file("keyring", text.as_bytes());
+    let keyring = Keyring::from_file(&path)?;
+    assert_eq!(keyring.len(), 2);
+    assert_eq!(keyring.identity(1), "bob");
+    assert_eq!(keyring.key(1), &Key::new([2u8; KEY_LEN]));
+
+    for (text, line) in [
+        (format!("alice {}\nbob\n", alice), 2),
+        (format!("alice {} extra\n", alice), 1),
+        (format!("alice {}\n", BASE64.encode(&[1u8; 16])), 1),
+        (String::from("alice not-base64\n"), 1),
+    ] {
+        fs::write(&path, text)?;
+        assert!(matches!(Keyring::from_file(&path), Err(KeyError::Malformed(_, l)) if l == line));
+    }
+    fs::write(&path, format!("alice {}\nalice {}\n", alice, bob))?;
+    assert!(matches!(
+        Keyring::from_file(&path),
+        Err(KeyError::Duplicate(..))
+    ));
+    fs::write(&path, "# nobody\n")?;
+    assert!(matches!(Keyring::from_file(&path), Err(KeyError::Empty(_))));
+    fs::remove_file(path)?;
+    Ok(())
+}
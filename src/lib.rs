@@ -1,2 +1,15 @@
+pub mod client;
+pub mod codec;
 pub mod dns_packet;
-pub mod payload;
\ No newline at end of file
+pub mod doh;
+pub mod handshake;
+pub mod keys;
+pub mod payload;
+pub mod relay;
+pub mod replay;
+pub mod server;
+pub mod session;
+pub mod socks;
+pub mod stream;
+pub mod tcp;
+pub mod tls;
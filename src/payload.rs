@@ -1,53 +1,130 @@
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, OsRng, Payload as AeadPayload},
     ChaCha20Poly1305, Nonce,
 };
-use typenum::U12;
 
 use core::mem::size_of;
-use std::fs::File;
-use std::{io::Read, path::Path};
+use std::error;
+use std::fmt;
+
+use crate::keys::Key;
+
+// Encrypted data goes in a frame telling how long it is, so whatever follows
+// it, e.g. padding up to whole records, is simply left out:
+//
+//     version (1) | flags (1) | length (2) | nonce (12) | ciphertext and tag
+//
+// The length is that of the ciphertext and tag. The header is authenticated
+// along with the ciphertext, and so is the associated data the caller gives,
+// which tells what the data is for. Data encrypted for one purpose then can't
+// be passed off as being for another.
+pub const VERSION: u8 = 1;
+const TAG_LEN: usize = 16;
+const NONCE_OFFSET: usize = 4;
+const HEADER_LEN: usize = NONCE_OFFSET + size_of::<Nonce>();
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PayloadError {
+    // Frame of a version we don't speak, or with flags we don't know
+    UnknownVersion(u8),
+    UnknownFlags(u8),
+    // Frame cut short, or data too long for one
+    Truncated,
+    TooLong(usize),
+    // Wrong key, or data changed on the way
+    Authentication,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadError::UnknownVersion(version) => {
+                write!(f, "Unknown payload version {}", version)
+            }
+            PayloadError::UnknownFlags(flags) => write!(f, "Unknown payload flags {:#04x}", flags),
+            PayloadError::Truncated => write!(f, "Payload truncated"),
+            PayloadError::TooLong(len) => write!(f, "Payload of {} bytes too long", len),
+            PayloadError::Authentication => write!(f, "Payload failed authentication"),
+        }
+    }
+}
+
+impl error::Error for PayloadError {}
 
 pub struct Payload {
     data: Vec<u8>,
     nonce: Nonce,
     cipher: ChaCha20Poly1305,
+    associated_data: Vec<u8>,
 }
 
 impl Payload {
-    pub fn new(data: Vec<u8>, key_path: &Path, nonce: Option<Nonce>) -> Self {
-        fn readkey(key_path: &Path) -> Result<ChaCha20Poly1305, std::io::Error> {
-            let mut buf = [0u8; 32];
-            File::open(key_path)?.read(&mut buf)?;
-            Ok(ChaCha20Poly1305::new_from_slice(&buf)
-                .map_err(|_| std::io::ErrorKind::InvalidData)?)
-        }
+    // Frame header and authentication tag added by encryption
+    pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+    pub fn new(data: Vec<u8>, key: &Key, nonce: Option<Nonce>) -> Self {
         Payload {
             data,
-            nonce: nonce
-                .or(Some(ChaCha20Poly1305::generate_nonce(&mut OsRng)))
-                .unwrap(),
-            cipher: readkey(key_path).unwrap_or(ChaCha20Poly1305::new(
-                &ChaCha20Poly1305::generate_key(&mut OsRng),
-            )),
+            nonce: nonce.unwrap_or_else(|| ChaCha20Poly1305::generate_nonce(&mut OsRng)),
+            cipher: key.cipher(),
+            associated_data: Vec::new(),
         }
     }
-    pub fn encrypt(&mut self) -> Result<(), aes_gcm::Error> {
-        self.data = self.cipher.encrypt(&self.nonce, self.data.as_ref())?;
-        self.data.extend_from_slice(self.nonce.as_slice());
+    pub fn set_associated_data(&mut self, associated_data: &[u8]) {
+        self.associated_data = associated_data.to_vec();
+    }
+    pub fn encrypt(&mut self) -> Result<(), PayloadError> {
+        let len = self.data.len() + TAG_LEN;
+        let len = u16::try_from(len).map_err(|_| PayloadError::TooLong(len))?;
+        let mut frame = vec![VERSION, 0];
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(self.nonce.as_slice());
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &self.nonce,
+                AeadPayload {
+                    msg: &self.data,
+                    aad: &[&frame, &self.associated_data[..]].concat(),
+                },
+            )
+            .map_err(|_| PayloadError::Authentication)?;
+        frame.extend_from_slice(&ciphertext);
+        self.data = frame;
         Ok(())
     }
-    pub fn decrypt(&mut self) -> Result<(), aes_gcm::Error> {
-        // TODO: Bugs here, if nonce contains zero in suffix
-        self.data
-            .truncate(self.data.len() - self.data.iter().rev().position(|&x| x != 0).unwrap());
-        let (data, nonce) = self.data.split_at(self.data.len() - size_of::<Nonce>());
-        self.nonce.copy_from_slice(&nonce);
-        self.data = self.cipher.decrypt(&self.nonce, data.as_ref())?;
+    // Anything after the frame is ignored
+    pub fn decrypt(&mut self) -> Result<(), PayloadError> {
+        if self.data.len() < HEADER_LEN {
+            return Err(PayloadError::Truncated);
+        }
+        let (header, rest) = self.data.split_at(HEADER_LEN);
+        if header[0] != VERSION {
+            return Err(PayloadError::UnknownVersion(header[0]));
+        }
+        if header[1] != 0 {
+            return Err(PayloadError::UnknownFlags(header[1]));
+        }
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let ciphertext = rest.get(..len).ok_or(PayloadError::Truncated)?;
+        self.nonce.copy_from_slice(&header[NONCE_OFFSET..]);
+        self.data = self
+            .cipher
+            .decrypt(
+                &self.nonce,
+                AeadPayload {
+                    msg: ciphertext,
+                    aad: &[header, &self.associated_data[..]].concat(),
+                },
+            )
+            .map_err(|_| PayloadError::Authentication)?;
         Ok(())
     }
     pub fn as_slice(&self) -> &[u8] {
-        &self.data.as_slice()
+        self.data.as_slice()
+    }
+    pub fn nonce(&self) -> &Nonce {
+        &self.nonce
     }
 }
 
@@ -70,3 +147,76 @@ impl std::fmt::Display for Payload {
         Ok(())
     }
 }
+
+// Tests
+
+#[cfg(test)]
+fn encrypted(data: &[u8], key: &Key, nonce: Nonce) -> Vec<u8> {
+    let mut payload = Payload::new(data.to_vec(), key, Some(nonce));
+    payload.encrypt().unwrap();
+    payload.as_slice().to_vec()
+}
+
+#[cfg(test)]
+fn decrypted(frame: &[u8], key: &Key) -> Result<Vec<u8>, PayloadError> {
+    let mut payload = Payload::new(frame.to_vec(), key, None);
+    payload.decrypt().map(|_| payload.as_slice().to_vec())
+}
+
+#[test]
+fn check_frame() {
+    let key = Key::new([0x42; 32]);
+    // Nonces ending in zeros, and padding of any kind after the frame
+    let nonce = Nonce::from([7, 7, 7, 7, 7, 7, 7, 7, 0, 0, 0, 0]);
+    let frame = encrypted(b"hello", &key, nonce);
+    assert_eq!(frame.len(), 5 + Payload::OVERHEAD);
+    assert_eq!(frame[..4], [VERSION, 0, 0, 5 + TAG_LEN as u8]);
+    for padding in [&[][..], &[0; 3], &[0xff; 11]] {
+        let padded = [&frame[..], padding].concat();
+        assert_eq!(decrypted(&padded, &key), Ok(b"hello".to_vec()));
+    }
+    assert_eq!(decrypted(&encrypted(b"", &key, nonce), &key), Ok(vec![]));
+
+    assert_eq!(
+        decrypted(&frame[..frame.len() - 1], &key),
+        Err(PayloadError::Truncated)
+    );
+    assert_eq!(decrypted(&frame[..3], &key), Err(PayloadError::Truncated));
+    assert_eq!(
+        decrypted(&frame, &Key::new([0x43; 32])),
+        Err(PayloadError::Authentication)
+    );
+    let mut other = frame.clone();
+    other[0] = VERSION + 1;
+    assert_eq!(
+        decrypted(&other, &key),
+        Err(PayloadError::UnknownVersion(VERSION + 1))
+    );
+    let mut other = frame.clone();
+    other[1] = 0x80;
+    assert_eq!(
+        decrypted(&other, &key),
+        Err(PayloadError::UnknownFlags(0x80))
+    );
+    // The length is authenticated, shortening the frame with it doesn't work
+    let mut other = frame.clone();
+    other[3] -= 1;
+    assert_eq!(decrypted(&other, &key), Err(PayloadError::Authentication));
+    // So is the associated data
+    let mut payload = Payload::new(frame.clone(), &key, None);
+    payload.set_associated_data(b"other");
+    assert_eq!(payload.decrypt(), Err(PayloadError::Authentication));
+    let mut payload = Payload::new(b"hello".to_vec(), &key, None);
+    payload.set_associated_data(b"this");
+    payload.encrypt().unwrap();
+    let mut other = Payload::new(payload.as_slice().to_vec(), &key, None);
+    other.set_associated_data(b"this");
+    assert_eq!(other.decrypt(), Ok(()));
+    assert_eq!(other.as_slice(), b"hello");
+
+    let mut payload = Payload::new(vec![0; u16::MAX as usize], &key, None);
+    assert_eq!(
+        payload.encrypt(),
+        Err(PayloadError::TooLong(u16::MAX as usize + TAG_LEN))
+    );
+}
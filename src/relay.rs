@@ -0,0 +1,443 @@
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use crate::stream::{Stream, WINDOW};
+
+// A session starts with the client saying what it wants in a message on the
+// stream. Connections to a host behind the server are answered with a message
+// telling whether the server could connect, after which the stream carries
+// the connection's data as is, and the end of the stream closes it.
+
+const READ_BUF_LEN: usize = 4096;
+// Reads and writes queued between the socket threads and the stream. The
+// socket is only read while the stream has no more than a few windows of data
+// left to send, so that a fast sender is held back by TCP flow control instead
+// of piling up here.
+const CHANNEL_LEN: usize = 4;
+const MAX_BUFFERED: usize = 4 * WINDOW as usize;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelayError {
+    BadRequest,
+    BadReply,
+    // Reason given by the server
+    ConnectFailed(String),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelayError::BadRequest => write!(f, "Bad session request"),
+            RelayError::BadReply => write!(f, "Bad reply to connection request"),
+            RelayError::ConnectFailed(reason) => write!(f, "Server failed to connect: {}", reason),
+        }
+    }
+}
+
+impl error::Error for RelayError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    // Messages for the server to print, answered by lines typed on its stdin
+    Chat,
+    // Connection to a host and port reachable from the server
+    Connect(String, u16),
+}
+
+impl Request {
+    const CHAT: u8 = 0;
+    const CONNECT: u8 = 1;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Request::Chat => vec![Self::CHAT],
+            Request::Connect(host, port) => {
+                let mut buf = vec![Self::CONNECT];
+                buf.extend_from_slice(&port.to_be_bytes());
+                buf.extend_from_slice(host.as_bytes());
+                buf
+            }
+        }
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, RelayError> {
+        match buf {
+            [Self::CHAT] => Ok(Request::Chat),
+            [Self::CONNECT, port_hi, port_lo, host @ ..] => {
+                let host = String::from_utf8(host.to_vec()).map_err(|_| RelayError::BadRequest)?;
+                Ok(Request::Connect(
+                    host,
+                    u16::from_be_bytes([*port_hi, *port_lo]),
+                ))
+            }
+            _ => Err(RelayError::BadRequest),
+        }
+    }
+}
+
+// First byte of the server's answer to a connection request, followed by the
+// reason if it failed
+const CONNECTED: u8 = 0;
+const CONNECT_FAILED: u8 = 1;
+
+// Take the server's answer to a connection request off the stream once it
+// arrived
+pub fn read_reply(stream: &mut Stream) -> Option<Result<(), RelayError>> {
+    match stream.read_message()?.as_slice() {
+        [CONNECTED] => Some(Ok(())),
+        [_, reason @ ..] => Some(Err(RelayError::ConnectFailed(
+            String::from_utf8_lossy(reason).into_owned(),
+        ))),
+        [] => Some(Err(RelayError::BadReply)),
+    }
+}
+
+enum Event {
+    Connected(TcpStream),
+    Failed(String),
+    Data(Vec<u8>),
+    Eof,
+}
+
+#[derive(PartialEq, Eq)]
+enum State {
+    // Server side, until the connection is made
+    Connecting,
+    // Client side, until the server answered
+    AwaitingReply,
+    Relaying,
+    Failed,
+}
+
+// TCP connection relayed over a stream. Blocking socket work happens in
+// threads of its own, `pump` moves data between them and the stream.
+pub struct Relay {
+    events: Receiver<Event>,
+    // Taken off the channel while waiting, not handled yet
+    pending: Option<Event>,
+    // Dropped to shut down the sending side of the socket
+    writer: Option<SyncSender<Vec<u8>>>,
+    // Taken off the stream, waiting for room in the writer's queue
+    unwritten: Vec<u8>,
+    socket: Option<TcpStream>,
+    state: State,
+    error: Option<RelayError>,
+}
+
+impl Relay {
+    // Relay a connection accepted by the client. The request for the server
+    // must already be written to the stream.
+    pub fn accept(socket: TcpStream) -> std::io::Result<Self> {
+        Self::spawn(socket, State::AwaitingReply)
+    }
+
+    // Relay a connection accepted by the client, which already has the
+    // server's answer from `read_reply`
+    pub fn established(socket: TcpStream) -> std::io::Result<Self> {
+        Self::spawn(socket, State::Relaying)
+    }
+
+    fn spawn(socket: TcpStream, state: State) -> std::io::Result<Self> {
+        let (event_tx, events) = mpsc::sync_channel(CHANNEL_LEN);
+        let (writer, writer_rx) = mpsc::sync_channel(CHANNEL_LEN);
+        let reader = socket.try_clone()?;
+        let sender = socket.try_clone()?;
+        thread::spawn(move || read_socket(reader, event_tx));
+        thread::spawn(move || write_socket(sender, writer_rx));
+        Ok(Relay {
+            events,
+            pending: None,
+            writer: Some(writer),
+            unwritten: Vec::new(),
+            socket: Some(socket),
+            state,
+            error: None,
+        })
+    }
+
+    // Connect to the host a client asked for, and relay the connection once
+    // it's made. Data the client sends in the meantime is held back.
+    pub fn connect(host: String, port: u16) -> Self {
+        let (event_tx, events) = mpsc::sync_channel(CHANNEL_LEN);
+        let (writer, writer_rx) = mpsc::sync_channel(CHANNEL_LEN);
+        thread::spawn(move || {
+            let socket = match TcpStream::connect((host.as_str(), port)) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    event_tx.send(Event::Failed(e.to_string())).ok();
+                    return;
+                }
+            };
+            let clones = socket
+                .try_clone()
+                .and_then(|s| Ok((s, socket.try_clone()?)));
+            let (sender, handle) = match clones {
+                Ok(clones) => clones,
+                Err(e) => {
+                    event_tx.send(Event::Failed(e.to_string())).ok();
+                    return;
+                }
+            };
+            // Nobody to tell if the relay is gone already
+            if event_tx.send(Event::Connected(handle)).is_err() {
+                return;
+            }
+            thread::spawn(move || write_socket(sender, writer_rx));
+            read_socket(socket, event_tx);
+        });
+        Relay {
+            events,
+            pending: None,
+            writer: Some(writer),
+            unwritten: Vec::new(),
+            socket: None,
+            state: State::Connecting,
+            error: None,
+        }
+    }
+
+    // Why the connection couldn't be relayed
+    pub fn error(&self) -> Option<&RelayError> {
+        self.error.as_ref()
+    }
+
+    // Wait until the socket has something for the stream, at most `timeout`
+    pub fn wait(&mut self, timeout: Duration) {
+        if self.pending.is_none() {
+            self.pending = self.events.recv_timeout(timeout).ok();
+        }
+    }
+
+    // Move whatever is there between the socket and the stream, as far as
+    // there's room on the other side
+    pub fn pump(&mut self, stream: &mut Stream) {
+        while stream.buffered() < MAX_BUFFERED {
+            let Some(event) = self.pending.take().or_else(|| self.events.try_recv().ok()) else {
+                break;
+            };
+            match event {
+                Event::Connected(socket) => {
+                    stream.write_message(&[CONNECTED]);
+                    self.socket = Some(socket);
+                    self.state = State::Relaying;
+                }
+                Event::Failed(reason) => {
+                    let mut reply = vec![CONNECT_FAILED];
+                    reply.extend_from_slice(reason.as_bytes());
+                    stream.write_message(&reply);
+                    self.fail(stream, RelayError::ConnectFailed(reason));
+                }
+                // The stream is closed already if the relay failed
+                Event::Data(_) | Event::Eof if self.state == State::Failed => (),
+                Event::Data(data) => stream.write(&data),
+                Event::Eof => stream.close(),
+            }
+        }
+
+        if self.state == State::AwaitingReply {
+            match read_reply(stream) {
+                None => return,
+                Some(Ok(())) => self.state = State::Relaying,
+                Some(Err(e)) => self.fail(stream, e),
+            }
+        }
+        if self.state == State::Failed {
+            stream.read();
+            return;
+        }
+        // Whatever the stream delivered goes to the writer until its queue is
+        // full, and the socket is only shut down once all of it went
+        while let Some(writer) = &self.writer {
+            if self.unwritten.is_empty() {
+                self.unwritten = stream.read();
+            }
+            if self.unwritten.is_empty() {
+                break;
+            }
+            if let Err(TrySendError::Full(data)) =
+                writer.try_send(std::mem::take(&mut self.unwritten))
+            {
+                self.unwritten = data;
+                break;
+            }
+        }
+        if stream.is_peer_closed() && self.unwritten.is_empty() {
+            self.writer = None;
+        }
+    }
+
+    fn fail(&mut self, stream: &mut Stream, error: RelayError) {
+        self.state = State::Failed;
+        self.error = Some(error);
+        self.writer = None;
+        if let Some(socket) = &self.socket {
+            socket.shutdown(Shutdown::Both).ok();
+        }
+        stream.close();
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        // Wakes up the thread blocked reading
+        if let Some(socket) = &self.socket {
+            socket.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+fn read_socket(mut socket: TcpStream, events: SyncSender<Event>) {
+    let mut buf = [0u8; READ_BUF_LEN];
+    loop {
+        let event = match socket.read(&mut buf) {
+            Ok(0) | Err(_) => Event::Eof,
+            Ok(len) => Event::Data(buf[..len].to_vec()),
+        };
+        let eof = matches!(event, Event::Eof);
+        if events.send(event).is_err() || eof {
+            return;
+        }
+    }
+}
+
+fn write_socket(mut socket: TcpStream, data: Receiver<Vec<u8>>) {
+    for data in data {
+        if socket.write_all(&data).is_err() {
+            return;
+        }
+    }
+    socket.shutdown(Shutdown::Write).ok();
+}
+
+// Tests
+
+#[test]
+fn check_request() -> Result<(), Box<dyn error::Error>> {
+    for request in [Request::Chat, Request::Connect("example.org".into(), 80)] {
+        assert_eq!(Request::deserialize(&request.serialize())?, request);
+    }
+    assert_eq!(Request::deserialize(&[1, 0]), Err(RelayError::BadRequest));
+    assert_eq!(Request::deserialize(&[]), Err(RelayError::BadRequest));
+    Ok(())
+}
+
+#[test]
+fn check_relay() -> Result<(), Box<dyn error::Error>> {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // Service behind the server, answering in upper case
+    let service = TcpListener::bind("127.0.0.1:0")?;
+    let service_port = service.local_addr()?.port();
+    thread::spawn(move || {
+        let (mut socket, _) = service.accept().unwrap();
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).unwrap();
+        socket.write_all(&data.to_ascii_uppercase()).unwrap();
+    });
+
+    // Application connecting to the client
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut app = TcpStream::connect(listener.local_addr()?)?;
+    let (accepted, _) = listener.accept()?;
+
+    let mut client_stream = Stream::new();
+    client_stream.write_message(&Request::Connect("127.0.0.1".into(), service_port).serialize());
+    let mut client = Relay::accept(accepted)?;
+    let mut server_stream = Stream::new();
+    let mut server = None;
+
+    app.write_all(b"hello")?;
+    app.shutdown(Shutdown::Write)?;
+    for _ in 0..1000 {
+        client.pump(&mut client_stream);
+        let segment = client_stream.next_segment(100, Instant::now());
+        server_stream.receive(segment)?;
+        if server.is_none() {
+            if let Some(request) = server_stream.read_message() {
+                match Request::deserialize(&request)? {
+                    Request::Connect(host, port) => server = Some(Relay::connect(host, port)),
+                    Request::Chat => return Err("unexpected chat".into()),
+                }
+            }
+        }
+        if let Some(server) = &mut server {
+            server.pump(&mut server_stream);
+            server.wait(Duration::from_millis(1));
+        }
+        let segment = server_stream.next_segment(100, Instant::now());
+        client_stream.receive(segment)?;
+        if client_stream.is_closed() && server_stream.is_closed() {
+            break;
+        }
+    }
+    assert!(client_stream.is_closed() && server_stream.is_closed());
+    let mut reply = Vec::new();
+    app.read_to_end(&mut reply)?;
+    assert_eq!(reply, b"HELLO");
+
+    // Unreachable hosts are reported to the client
+    let closed_port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let mut server_stream = Stream::new();
+    let mut server = Relay::connect("127.0.0.1".into(), closed_port);
+    server.wait(Duration::from_secs(5));
+    server.pump(&mut server_stream);
+    let mut client_stream = Stream::new();
+    let _app = TcpStream::connect(listener.local_addr()?)?;
+    let (accepted, _) = listener.accept()?;
+    let mut client = Relay::accept(accepted)?;
+    client_stream.receive(server_stream.next_segment(100, Instant::now()))?;
+    client.pump(&mut client_stream);
+    assert!(matches!(client.error(), Some(RelayError::ConnectFailed(_))));
+    Ok(())
+}
+
+#[test]
+fn check_backpressure() -> Result<(), Box<dyn error::Error>> {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // Service sending far more than the tunnel carries at a time
+    let data: Vec<u8> = (0..=255).cycle().take(4 << 20).collect();
+    let service = TcpListener::bind("127.0.0.1:0")?;
+    let service_port = service.local_addr()?.port();
+    let sent = data.clone();
+    thread::spawn(move || {
+        let (mut socket, _) = service.accept().unwrap();
+        socket.write_all(&sent).unwrap();
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut app = TcpStream::connect(listener.local_addr()?)?;
+    let (accepted, _) = listener.accept()?;
+    let reader = thread::spawn(move || {
+        let mut received = Vec::new();
+        app.read_to_end(&mut received).map(|_| received)
+    });
+    let mut client = Relay::accept(accepted)?;
+    let mut client_stream = Stream::new();
+    let mut server = Relay::connect("127.0.0.1".into(), service_port);
+    let mut server_stream = Stream::new();
+
+    // What the stream holds stays bounded however fast the service sends
+    let mut now = Instant::now();
+    for _ in 0..10000 {
+        server.wait(Duration::from_millis(1));
+        server.pump(&mut server_stream);
+        assert!(server_stream.buffered() <= MAX_BUFFERED + READ_BUF_LEN);
+        now += Duration::from_millis(100);
+        client_stream.receive(server_stream.next_segment(8000, now))?;
+        client.pump(&mut client_stream);
+        server_stream.receive(client_stream.next_segment(8000, now))?;
+        if reader.is_finished() {
+            break;
+        }
+    }
+    assert!(reader.join().unwrap()? == data);
+    Ok(())
+}
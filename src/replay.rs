@@ -0,0 +1,101 @@
+use chacha20poly1305::Nonce;
+
+// Messages of a session are numbered in each direction, from 0, and the
+// number goes in the nonce. Receivers remember which of the latest numbers
+// they've seen, and drop messages with a number seen before or too old to
+// tell, after checking they're genuine. Each direction has keys of its own,
+// so no nonce is used twice with the same key.
+
+// How far behind the latest message others may arrive
+pub const WINDOW: u64 = 64;
+// Nonce bytes before the number, always zero
+const PREFIX_LEN: usize = 4;
+
+pub fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+pub fn counter(nonce: &Nonce) -> Option<u64> {
+    let (prefix, counter) = nonce.split_at(PREFIX_LEN);
+    if prefix.iter().any(|&b| b != 0) {
+        return None;
+    }
+    Some(u64::from_be_bytes(counter.try_into().ok()?))
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    // One more than the highest number seen, and which of the numbers below
+    // it were seen, bit i standing for next - 1 - i
+    next: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record that the message numbered `counter` arrived. Returns whether it
+    // is the first time, false meaning it must be dropped.
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if counter >= self.next {
+            let Some(next) = counter.checked_add(1) else {
+                return false;
+            };
+            let shift = next - self.next;
+            self.seen = if shift >= WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = next;
+            return true;
+        }
+        let age = self.next - 1 - counter;
+        if age >= WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+// Tests
+
+#[test]
+fn check_nonce() {
+    for n in [0, 1, 0x0102_0304_0506_0708, u64::MAX] {
+        assert_eq!(counter(&nonce(n)), Some(n));
+    }
+    let mut other = nonce(1);
+    other[0] = 1;
+    assert_eq!(counter(&other), None);
+}
+
+#[test]
+fn check_window() {
+    let mut window = ReplayWindow::new();
+    assert!(window.accept(0));
+    assert!(!window.accept(0));
+    // Out of order, but each only once
+    for n in [3, 1, 2] {
+        assert!(window.accept(n));
+        assert!(!window.accept(n));
+    }
+    // Skipping ahead leaves the gap open as long as it's in the window
+    assert!(window.accept(3 + WINDOW));
+    assert!(!window.accept(3));
+    assert!(window.accept(4));
+    assert!(!window.accept(4));
+    assert!(window.accept(2 + WINDOW));
+    // Far ahead, everything before is too old
+    assert!(window.accept(1000));
+    assert!(!window.accept(1000 - WINDOW));
+    assert!(window.accept(1001 - WINDOW));
+    assert!(window.accept(u64::MAX - 1));
+    assert!(!window.accept(u64::MAX));
+}
@@ -0,0 +1,947 @@
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::dns_packet::{DnsName, Packet, ResponseCode};
+use crate::handshake::{Handshake, SessionKeys, PUBLIC_LEN};
+use crate::keys::{Key, Keyring};
+use crate::payload::Payload;
+use crate::replay::{self, ReplayWindow};
+use crate::session::{self, Direction, NONCE_LEN, OPEN_ID, PROBE_ID};
+use crate::stream::{Segment, Stream};
+use crate::tcp::MAX_TCP_LEN;
+
+// Number of recent replies remembered for answering retried queries
+const REPLY_CACHE_SIZE: usize = 1024;
+// Sessions are closed after going this long without a query, and no more are
+// opened while this many are open
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_SESSIONS: usize = 1024;
+
+struct Session {
+    // Index of the client in the keyring
+    client: usize,
+    // Nonce the client asked for the session with, our ephemeral public key
+    // sent in response, and the keys agreed on
+    nonce: [u8; NONCE_LEN],
+    public: [u8; PUBLIC_LEN],
+    keys: SessionKeys,
+    // Number of the next message we send, and the numbers of those received
+    sent: u64,
+    received: ReplayWindow,
+    stream: Stream,
+    last_active: Instant,
+}
+
+// Authoritative nameserver for the tunnel domain. Queries carrying tunnel data
+// are decrypted and answered, everything else in the zone is answered the way
+// any authoritative server would, so that recursive resolvers accept us.
+pub struct Server {
+    domain: DnsName,
+    nameserver: DnsName,
+    keys: Keyring,
+
+    // Resolvers retry queries they didn't get an answer for in time, possibly
+    // with a different letter case. Each query must only be processed once, so
+    // retries are answered with the data the first reply carried.
+    reply_cache: HashMap<String, Vec<u8>>,
+    cache_order: VecDeque<String>,
+
+    sessions: HashMap<u16, Session>,
+    // Upstream keys of sessions closed within the session timeout, and when,
+    // to tell their clients apart from anyone guessing at names
+    closed: HashMap<u16, (Key, Instant)>,
+    // Queries dropped for carrying a message which already arrived
+    replays: u64,
+}
+
+impl Server {
+    pub fn new(domain: DnsName, nameserver: DnsName, keys: Keyring) -> Self {
+        Server {
+            domain,
+            nameserver,
+            keys,
+            reply_cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            sessions: HashMap::new(),
+            closed: HashMap::new(),
+            replays: 0,
+        }
+    }
+
+    // Build the response to a single DNS query, as long as the client takes
+    // over UDP. Errors mean the query should be dropped without an answer.
+    pub fn handle(&mut self, query: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        self.respond(query, false)
+    }
+
+    // Same for a query which came over TCP, where responses can be as long as
+    // the length prefix allows
+    pub fn handle_tcp(&mut self, query: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        self.respond(query, true)
+    }
+
+    fn respond(&mut self, query: &[u8], over_tcp: bool) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let mut request = Packet::new(false);
+        request.deserialize(query)?;
+        if request.is_response() {
+            return Err("unexpected response".into());
+        }
+
+        let max_len = if over_tcp {
+            MAX_TCP_LEN
+        } else {
+            request.max_response_len()
+        };
+        let mut reply = Packet::response_to(&request);
+        if request.edns().is_some_and(|edns| edns.version != 0) {
+            reply.set_rcode(ResponseCode::BadVersion);
+        } else if !request.in_zone(&self.domain) || !request.is_internet_class() {
+            reply.set_rcode(ResponseCode::Refused);
+        } else if request.is_apex_query(&self.domain) {
+            reply.answer_apex(&self.domain, &self.nameserver)?;
+        } else if !request.asks_for_data() {
+            reply.set_nodata(&self.domain, &self.nameserver)?;
+        } else {
+            let now = Instant::now();
+            self.expire_sessions(now);
+            let key = request.question_key(&self.domain);
+            let data = match self.reply_cache.get(&key) {
+                Some(data) => Ok(data.clone()),
+                None => self.process(&request, max_len, now).inspect(|data| {
+                    self.remember(key, data.clone());
+                }),
+            };
+            match data {
+                // Answered in the codec the query came in
+                Ok(data) => {
+                    let embedded = request.codec(&self.domain).is_some_and(|codec| {
+                        reply
+                            .embed_data(&data, Some(&request), &self.domain, codec)
+                            .is_ok()
+                    });
+                    if !embedded {
+                        reply.set_nodata(&self.domain, &self.nameserver)?;
+                    }
+                }
+                // Names in our zone which don't carry tunnel data exist, but
+                // have nothing to offer. Those of closed sessions don't.
+                Err(rcode) => {
+                    reply.set_nodata(&self.domain, &self.nameserver)?;
+                    reply.set_rcode(rcode);
+                }
+            }
+        }
+        let buf = reply.serialize(request.id())?.into_vec();
+        if buf.len() > max_len {
+            // Long queries leave no room for the SOA record of a nodata answer,
+            // and data remembered for a query may not fit a retry offering
+            // less. The client can ask again over TCP for all of it.
+            let mut truncated = Packet::response_to(&request);
+            truncated.set_rcode(reply.rcode());
+            truncated.set_truncated(true);
+            return Ok(truncated.serialize(request.id())?.into_vec());
+        }
+        Ok(buf)
+    }
+
+    pub fn session_ids(&self) -> Vec<u16> {
+        self.sessions.keys().copied().collect()
+    }
+
+    // Stream of an open session. Data written to it is queued until the
+    // client polls for it.
+    pub fn stream(&mut self, id: u16) -> Option<&mut Stream> {
+        self.sessions
+            .get_mut(&id)
+            .map(|session| &mut session.stream)
+    }
+
+    // Identity of the client a session belongs to
+    pub fn client(&self, id: u16) -> Option<&str> {
+        self.sessions
+            .get(&id)
+            .map(|session| self.keys.identity(session.client))
+    }
+
+    // Number of queries dropped as replays. Retries of a query are answered
+    // with the same reply instead as long as it's remembered.
+    pub fn replays(&self) -> u64 {
+        self.replays
+    }
+
+    pub fn close(&mut self, id: u16) {
+        self.close_at(id, Instant::now());
+    }
+
+    fn close_at(&mut self, id: u16, now: Instant) {
+        let Some(session) = self.sessions.remove(&id) else {
+            return;
+        };
+        if self.closed.len() >= MAX_SESSIONS {
+            let oldest = self.closed.iter().min_by_key(|(_, (_, at))| *at);
+            if let Some(&oldest) = oldest.map(|(id, _)| id) {
+                self.closed.remove(&oldest);
+            }
+        }
+        self.closed.insert(id, (session.keys.upstream, now));
+    }
+
+    // Close sessions which have been idle for too long, and forget those
+    // closed long enough ago
+    pub fn expire_sessions(&mut self, now: Instant) {
+        self.closed
+            .retain(|_, (_, at)| now.duration_since(*at) < SESSION_TIMEOUT);
+        let idle: Vec<u16> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_active) >= SESSION_TIMEOUT)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in idle {
+            self.close_at(id, now);
+        }
+    }
+
+    // Decrypt the data carried by a query and produce the data for the
+    // response, which must fit in `max_len` bytes. Errors give the response
+    // code to answer with instead.
+    fn process(
+        &mut self,
+        request: &Packet,
+        max_len: usize,
+        now: Instant,
+    ) -> Result<Vec<u8>, ResponseCode> {
+        let data = request
+            .extract_data(&self.domain)
+            .map_err(|_| ResponseCode::NoError)?;
+        let (id, data) = session::split_id(&data).ok_or(ResponseCode::NoError)?;
+        // The client has to start over if its session is gone. Names which
+        // only look like they carry data for one, such as those resolvers
+        // minimising queries ask for, exist like any other name.
+        if id != OPEN_ID && id != PROBE_ID && !self.sessions.contains_key(&id) {
+            return Err(if self.was_closed(id, data) {
+                ResponseCode::NameError
+            } else {
+                ResponseCode::NoError
+            });
+        }
+        let (client, payload) = self.decrypt(id, data).ok_or(ResponseCode::NoError)?;
+
+        let codec = request.codec(&self.domain).ok_or(ResponseCode::NoError)?;
+        let capacity = request
+            .response_capacity(&self.domain, codec, max_len)
+            .checked_sub(Payload::OVERHEAD)
+            .ok_or(ResponseCode::NoError)?;
+        let reply = if id == OPEN_ID {
+            self.open(client, payload.as_slice(), now)?
+        } else if id == PROBE_ID {
+            Self::probe(payload.as_slice(), capacity)?
+        } else {
+            let session = self.sessions.get_mut(&id).ok_or(ResponseCode::NameError)?;
+            let counter = replay::counter(payload.nonce()).ok_or(ResponseCode::NoError)?;
+            if !session.received.accept(counter) {
+                self.replays += 1;
+                return Err(ResponseCode::NoError);
+            }
+            session.last_active = now;
+            let capacity = capacity
+                .checked_sub(Segment::HEADER_LEN)
+                .ok_or(ResponseCode::NoError)?;
+            Self::exchange(&mut session.stream, payload.as_slice(), capacity, now)?
+        };
+        let (key, nonce) = match self.sessions.get_mut(&id) {
+            Some(session) => {
+                let counter = session.sent;
+                session.sent += 1;
+                (&session.keys.downstream, Some(replay::nonce(counter)))
+            }
+            None => (self.keys.key(client), None),
+        };
+        let mut reply_payload = Payload::new(reply, key, nonce);
+        reply_payload.set_associated_data(&session::associated_data(id, Direction::Downstream));
+        reply_payload
+            .encrypt()
+            .map_err(|_| ResponseCode::ServerFailure)?;
+        Ok(reply_payload.as_slice().to_vec())
+    }
+
+    // Decrypt the data of a query with the session's key if it's for one.
+    // Other queries carry nothing telling who sent them, and are decrypted
+    // with whichever pre-shared key works.
+    fn decrypt(&self, id: u16, data: &[u8]) -> Option<(usize, Payload)> {
+        let associated_data = session::associated_data(id, Direction::Upstream);
+        if let Some(session) = self.sessions.get(&id) {
+            let mut payload = Payload::new(data.to_vec(), &session.keys.upstream, None);
+            payload.set_associated_data(&associated_data);
+            return payload.decrypt().ok().map(|_| (session.client, payload));
+        }
+        (0..self.keys.len()).find_map(|client| {
+            let mut payload = Payload::new(data.to_vec(), self.keys.key(client), None);
+            payload.set_associated_data(&associated_data);
+            payload.decrypt().ok().map(|_| (client, payload))
+        })
+    }
+
+    // Whether the data of a query is genuine for a session closed lately
+    fn was_closed(&self, id: u16, data: &[u8]) -> bool {
+        self.closed.get(&id).is_some_and(|(key, _)| {
+            let mut payload = Payload::new(data.to_vec(), key, None);
+            payload.set_associated_data(&session::associated_data(id, Direction::Upstream));
+            payload.decrypt().is_ok()
+        })
+    }
+
+    // Set up a session for the client, or find the one set up for an earlier
+    // copy of the same request
+    fn open(
+        &mut self,
+        client: usize,
+        request: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, ResponseCode> {
+        let (nonce, peer) = request
+            .split_first_chunk::<NONCE_LEN>()
+            .ok_or(ResponseCode::NoError)?;
+        let peer: &[u8; PUBLIC_LEN] = peer.try_into().map_err(|_| ResponseCode::NoError)?;
+        let existing = self
+            .sessions
+            .iter()
+            .find(|(_, session)| session.client == client && session.nonce == *nonce)
+            .map(|(&id, session)| (id, session.public));
+        let (id, public) = match existing {
+            Some(existing) => existing,
+            None => {
+                if self.sessions.len() >= MAX_SESSIONS {
+                    return Err(ResponseCode::Refused);
+                }
+                let id = loop {
+                    let id = OsRng.next_u32() as u16;
+                    if id != OPEN_ID
+                        && id != PROBE_ID
+                        && !self.sessions.contains_key(&id)
+                        && !self.closed.contains_key(&id)
+                    {
+                        break id;
+                    }
+                };
+                let handshake = Handshake::new();
+                let public = handshake.public();
+                let keys = handshake
+                    .finish(peer, self.keys.key(client), false)
+                    .ok_or(ResponseCode::NoError)?;
+                self.sessions.insert(
+                    id,
+                    Session {
+                        client,
+                        nonce: *nonce,
+                        public,
+                        keys,
+                        sent: 0,
+                        received: ReplayWindow::new(),
+                        stream: Stream::new(),
+                        last_active: now,
+                    },
+                );
+                (id, public)
+            }
+        };
+        let mut reply = nonce.to_vec();
+        reply.extend_from_slice(&id.to_be_bytes());
+        reply.extend_from_slice(&public);
+        Ok(reply)
+    }
+
+    // Echo the probe's nonce and fill the rest of the response with the
+    // pattern, for the client to check what arrives
+    fn probe(nonce: &[u8], capacity: usize) -> Result<Vec<u8>, ResponseCode> {
+        if nonce.len() != NONCE_LEN {
+            return Err(ResponseCode::NoError);
+        }
+        let mut reply = nonce.to_vec();
+        reply.extend(session::probe_pattern(capacity.saturating_sub(NONCE_LEN)));
+        Ok(reply)
+    }
+
+    // Feed a segment to the session's stream, and produce the segment for the
+    // response from whatever is queued
+    fn exchange(
+        stream: &mut Stream,
+        segment: &[u8],
+        capacity: usize,
+        now: Instant,
+    ) -> Result<Vec<u8>, ResponseCode> {
+        let segment = Segment::deserialize(segment).map_err(|_| ResponseCode::NoError)?;
+        stream.receive(segment).map_err(|_| ResponseCode::NoError)?;
+        Ok(stream.next_segment(capacity, now).serialize())
+    }
+
+    fn remember(&mut self, key: String, data: Vec<u8>) {
+        if self.cache_order.len() >= REPLY_CACHE_SIZE {
+            if let Some(oldest) = self.cache_order.pop_front() {
+                self.reply_cache.remove(&oldest);
+            }
+        }
+        self.cache_order.push_back(key.clone());
+        self.reply_cache.insert(key, data);
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_setup() -> Result<(Server, crate::client::Client, crate::keys::Key), Box<dyn error::Error>>
+{
+    use std::str::FromStr;
+
+    let key = crate::keys::Key::new([0x42u8; 32]);
+    let domain = DnsName::from_str("t.example.org")?;
+    let keys = Keyring::single(key.clone());
+    let server = Server::new(domain.clone(), DnsName::from_str("ns.example.org")?, keys);
+    let client = crate::client::Client::new(domain, key.clone());
+    Ok((server, client, key))
+}
+
+#[cfg(test)]
+fn run_exchange(server: &mut Server, client: &mut crate::client::Client) {
+    let query = client.next_query(Instant::now()).unwrap();
+    let response = server.handle(&query).unwrap();
+    // Undecryptable responses just count as lost
+    client.handle_response(&response).ok();
+}
+
+// Have the server echo `message` back to the client, which takes several
+// responses if it's long
+#[cfg(test)]
+fn run_echo(
+    server: &mut Server,
+    client: &mut crate::client::Client,
+    message: &[u8],
+) -> Option<Vec<u8>> {
+    client.stream().write_message(message);
+    for _ in 0..10 {
+        if client.session().is_some() {
+            break;
+        }
+        run_exchange(server, client);
+    }
+    let id = client.session()?;
+    let mut reply = None;
+    for _ in 0..100 {
+        run_exchange(server, client);
+        let stream = server.stream(id)?;
+        if let Some(message) = stream.read_message() {
+            stream.write_message(&message);
+        }
+        reply = reply.or_else(|| client.stream().read_message());
+    }
+    reply
+}
+
+#[test]
+fn check_sessions() -> Result<(), Box<dyn error::Error>> {
+    let (mut server, client, key) = test_setup()?;
+    let mut other = crate::client::Client::new(server.domain.clone(), key.clone());
+    other.set_record_type(crate::dns_packet::RecordType::A);
+
+    // Two clients taking turns, each getting the reply to its own message in
+    // the record type it asks for
+    let mut clients = [client, other];
+    clients[0].stream().write_message(&[1; 100]);
+    clients[1].stream().write_message(&[2; 10]);
+    let mut replies = [None, None];
+    for _ in 0..100 {
+        for (client, reply) in clients.iter_mut().zip(&mut replies) {
+            run_exchange(&mut server, client);
+            if reply.is_none() {
+                *reply = client.stream().read_message();
+            }
+        }
+        // Answer every message with its length
+        for id in server.session_ids() {
+            let stream = server.stream(id).unwrap();
+            while let Some(message) = stream.read_message() {
+                stream.write_message(&(message.len() as u32).to_be_bytes());
+            }
+        }
+    }
+    assert_eq!(replies[0].as_deref(), Some(&100u32.to_be_bytes()[..]));
+    assert_eq!(replies[1].as_deref(), Some(&10u32.to_be_bytes()[..]));
+    assert_ne!(clients[0].session(), clients[1].session());
+    assert_eq!(server.sessions.len(), 2);
+
+    // Idle sessions get closed, which their clients learn on the next query
+    server.expire_sessions(Instant::now() + SESSION_TIMEOUT);
+    assert!(server.sessions.is_empty());
+    let query = clients[0].next_query(Instant::now())?;
+    let response = server.handle(&query)?;
+    assert!(clients[0].handle_response(&response).is_err());
+
+    // Names which only look like they carry data for a session exist but are
+    // empty, whether the session is gone or never was
+    let rcode = |server: &mut Server, data: &[u8]| -> Result<_, Box<dyn error::Error>> {
+        let mut query = Packet::new(false);
+        query.embed_data(data, None, &server.domain, &crate::codec::Base32)?;
+        let mut reply = Packet::new(true);
+        reply.deserialize(&server.handle(query.serialize(1)?.as_raw_slice())?)?;
+        Ok(reply.rcode())
+    };
+    let mut request = Packet::new(false);
+    request.deserialize(&clients[1].next_query(Instant::now())?)?;
+    let mut data = request.extract_data(&server.domain)?;
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    assert_eq!(rcode(&mut server, &data)?, ResponseCode::NoError);
+    data[last] ^= 1;
+    assert_eq!(rcode(&mut server, &data)?, ResponseCode::NameError);
+    data[..2].copy_from_slice(&0x1234u16.to_be_bytes());
+    assert_eq!(rcode(&mut server, &data)?, ResponseCode::NoError);
+    assert_eq!(rcode(&mut server, &data[..3])?, ResponseCode::NoError);
+
+    Ok(())
+}
+
+#[test]
+fn check_session_keys() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::RecordType;
+
+    let (mut server, mut client, key) = test_setup()?;
+    client.set_record_type(RecordType::TXT);
+    assert_eq!(
+        run_echo(&mut server, &mut client, b"hi"),
+        Some(b"hi".to_vec())
+    );
+
+    // Once the session is set up, its queries are encrypted with its own
+    // keys rather than the pre-shared one
+    let id = client.session().unwrap();
+    let mut query = Packet::new(false);
+    query.deserialize(&client.next_query(Instant::now())?)?;
+    let data = query.extract_data(&server.domain)?;
+    let (query_id, data) = session::split_id(&data).unwrap();
+    assert_eq!(query_id, id);
+    let mut payload = Payload::new(data.to_vec(), &key, None);
+    payload.set_associated_data(&session::associated_data(id, Direction::Upstream));
+    assert!(payload.decrypt().is_err());
+    assert!(server.decrypt(id, data).is_some());
+    let upstream = server.sessions[&id].keys.upstream.clone();
+
+    // Other sessions of the same client get keys of their own
+    let mut other = crate::client::Client::new(server.domain.clone(), key);
+    other.set_record_type(RecordType::TXT);
+    assert!(run_echo(&mut server, &mut other, b"hi").is_some());
+    let other = &server.sessions[&other.session().unwrap()];
+    assert_ne!(other.keys.upstream, upstream);
+    Ok(())
+}
+
+#[test]
+fn check_associated_data() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::RecordType;
+
+    let (mut server, mut client, key) = test_setup()?;
+    client.set_record_type(RecordType::TXT);
+    assert!(run_echo(&mut server, &mut client, b"hi").is_some());
+    let id = client.session().unwrap();
+    let encrypted = |key, id, direction| {
+        let mut payload = Payload::new(b"data".to_vec(), key, None);
+        payload.set_associated_data(&session::associated_data(id, direction));
+        payload.encrypt().unwrap();
+        payload.as_slice().to_vec()
+    };
+
+    // Messages only decrypt as what they were sent as: a probe doesn't pass
+    // for a request for a session, nor a response for a query
+    let probe = encrypted(&key, PROBE_ID, Direction::Upstream);
+    assert!(server.decrypt(PROBE_ID, &probe).is_some());
+    assert!(server.decrypt(OPEN_ID, &probe).is_none());
+    let response = encrypted(&key, PROBE_ID, Direction::Downstream);
+    assert!(server.decrypt(PROBE_ID, &response).is_none());
+
+    // Same within a session
+    let upstream = server.sessions[&id].keys.upstream.clone();
+    assert!(server
+        .decrypt(id, &encrypted(&upstream, id, Direction::Upstream))
+        .is_some());
+    assert!(server
+        .decrypt(id, &encrypted(&upstream, id, Direction::Downstream))
+        .is_none());
+    assert!(server
+        .decrypt(id, &encrypted(&upstream, id ^ 1, Direction::Upstream))
+        .is_none());
+    Ok(())
+}
+
+#[test]
+fn check_replays() -> Result<(), Box<dyn error::Error>> {
+    use crate::client::ClientError;
+    use crate::dns_packet::RecordType;
+
+    let (mut server, mut client, _) = test_setup()?;
+    client.set_record_type(RecordType::TXT);
+    assert!(run_echo(&mut server, &mut client, b"hi").is_some());
+    let id = client.session().unwrap();
+    client.stream().write_message(b"once");
+    let query = client.next_query(Instant::now())?;
+    let response = server.handle(&query)?;
+    assert!(client.handle_response(&response)?);
+    let stream = server.stream(id).unwrap();
+    assert_eq!(stream.read_message().as_deref(), Some(&b"once"[..]));
+
+    // Retries get the reply remembered for the query, replays of a query
+    // long forgotten are dropped
+    server.handle(&query)?;
+    assert_eq!(server.replays(), 0);
+    server.reply_cache.clear();
+    server.handle(&query)?;
+    assert_eq!(server.replays(), 1);
+
+    // Same for responses replayed to the client, as the response to a later
+    // query
+    let query = client.next_query(Instant::now())?;
+    let mut replayed = response.clone();
+    replayed[..2].copy_from_slice(&query[..2]);
+    let error = client.handle_response(&replayed).unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&ClientError::Replayed));
+    assert_eq!(client.replays(), 1);
+    Ok(())
+}
+
+#[test]
+fn check_client_keys() -> Result<(), Box<dyn error::Error>> {
+    use crate::client::Client;
+    use crate::dns_packet::RecordType;
+    use crate::keys::{Key, Keyring};
+    use data_encoding::BASE64;
+
+    let (mut server, _, _) = test_setup()?;
+    let path = std::env::temp_dir().join(format!("dns-camo-keyring-{}", std::process::id()));
+    let alice = Key::new([1; 32]);
+    let bob = Key::new([2; 32]);
+    std::fs::write(
+        &path,
+        format!(
+            "alice {}\nbob {}\n",
+            BASE64.encode(&[1; 32]),
+            BASE64.encode(&[2; 32])
+        ),
+    )?;
+    server.keys = Keyring::from_file(&path)?;
+    std::fs::remove_file(path)?;
+
+    // Every client gets a session of its own, whichever key it has
+    let mut clients = [alice, bob, Key::new([3; 32])].map(|key| {
+        let mut client = Client::new(server.domain.clone(), key);
+        client.set_record_type(RecordType::TXT);
+        client
+    });
+    for (client, identity) in clients[..2].iter_mut().zip(["alice", "bob"]) {
+        let reply = run_echo(&mut server, client, identity.as_bytes());
+        assert_eq!(reply.as_deref(), Some(identity.as_bytes()));
+        assert_eq!(server.client(client.session().unwrap()), Some(identity));
+    }
+    // Clients whose key isn't in the keyring get nowhere
+    assert_eq!(run_echo(&mut server, &mut clients[2], b"mallory"), None);
+    assert_eq!(server.sessions.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn check_record_types() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::RecordType;
+
+    let (mut server, _, key) = test_setup()?;
+    let message: Vec<u8> = (0..=255).cycle().take(400).collect();
+    for record_type in [
+        RecordType::AAAA,
+        RecordType::CNAME,
+        RecordType::NULL,
+        RecordType::MX,
+        RecordType::SRV,
+    ] {
+        let mut client = crate::client::Client::new(server.domain.clone(), key.clone());
+        client.set_record_type(record_type);
+        let reply = run_echo(&mut server, &mut client, &message);
+        assert_eq!(reply.as_ref(), Some(&message), "{:?}", record_type);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_codecs() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::RecordType;
+
+    // Data goes in names both ways with CNAME answers
+    let (mut server, _, key) = test_setup()?;
+    let message: Vec<u8> = (0..=255).cycle().take(400).collect();
+    for codec in crate::codec::CODECS {
+        let mut client = crate::client::Client::new(server.domain.clone(), key.clone());
+        client.set_record_type(RecordType::CNAME);
+        client.set_codec(codec);
+        let reply = run_echo(&mut server, &mut client, &message);
+        assert_eq!(reply.as_ref(), Some(&message), "{:?}", codec);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_reply_cache_case() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::RecordType;
+
+    // Same query with the letters of its first label in the other case
+    let flip_case = |query: &[u8]| {
+        let mut query = query.to_vec();
+        let len = query[12] as usize;
+        query[13..13 + len]
+            .iter_mut()
+            .for_each(|byte| *byte ^= if byte.is_ascii_alphabetic() { 0x20 } else { 0 });
+        query
+    };
+    let answers = |response: &[u8]| u16::from_be_bytes([response[6], response[7]]);
+
+    // Resolvers may change the case of base32 names on retries, which still get
+    // the reply remembered for the query
+    let (mut server, _, key) = test_setup()?;
+    for (codec, same) in [
+        (&crate::codec::Base32 as &dyn crate::codec::LabelCodec, true),
+        (&crate::codec::Base64, false),
+    ] {
+        let mut client = crate::client::Client::new(server.domain.clone(), key.clone());
+        client.set_record_type(RecordType::TXT);
+        client.set_codec(codec);
+        while client.session().is_none() {
+            run_exchange(&mut server, &mut client);
+        }
+        let query = client.next_query(Instant::now())?;
+        let response = server.handle(&query)?;
+        assert_eq!(answers(&response), 1);
+        // In base64 the other case is other data, which isn't genuine
+        let other = flip_case(&query);
+        assert_ne!(other, query);
+        let response = server.handle(&other)?;
+        assert_eq!(answers(&response), same as u16, "{:?}", codec);
+        assert!(client.handle_response(&server.handle(&query)?)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn check_edns() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::{Edns, RecordType, MAX_EDNS_UDP_LEN, MAX_UDP_LEN};
+
+    // Responses grow to the size the client advertised
+    let (mut server, _, key) = test_setup()?;
+    let long: Vec<u8> = (0..=255).cycle().take(4000).collect();
+    for (udp_len, max_len) in [
+        (MAX_EDNS_UDP_LEN as u16, MAX_EDNS_UDP_LEN),
+        (512, MAX_UDP_LEN),
+    ] {
+        let mut client = crate::client::Client::new(server.domain.clone(), key.clone());
+        client.set_record_type(RecordType::TXT);
+        client.set_udp_len(udp_len);
+        while client.session().is_none() {
+            run_exchange(&mut server, &mut client);
+        }
+        let id = client.session().ok_or("no session")?;
+        server.stream(id).ok_or("no stream")?.write_message(&long);
+        let query = client.next_query(Instant::now())?;
+        let response = server.handle(&query)?;
+        assert_eq!(response.len(), max_len);
+        client.handle_response(&response)?;
+    }
+
+    // Only version 0 is known
+    let mut query = Packet::new(false);
+    query.embed_data(b"data", None, &server.domain, &crate::codec::Base32)?;
+    let mut edns = Edns::new(1232);
+    edns.version = 1;
+    query.set_edns(Some(edns));
+    let response = server.handle(query.serialize(1)?.as_raw_slice())?;
+    let mut reply = Packet::new(true);
+    reply.deserialize(&response)?;
+    assert_eq!(reply.rcode(), ResponseCode::BadVersion);
+
+    Ok(())
+}
+
+#[test]
+fn check_tcp() -> Result<(), Box<dyn error::Error>> {
+    use crate::client::ClientError;
+    use crate::dns_packet::{RecordType, MAX_EDNS_UDP_LEN, OPT_LEN};
+
+    let (mut server, mut client, _) = test_setup()?;
+    client.set_record_type(RecordType::TXT);
+    while client.session().is_none() {
+        run_exchange(&mut server, &mut client);
+    }
+    let id = client.session().ok_or("no session")?;
+    let long: Vec<u8> = (0..=255).cycle().take(20000).collect();
+    server.stream(id).ok_or("no stream")?.write_message(&long);
+
+    // A resolver retrying with a smaller payload size gets the remembered
+    // response cut off, and the client asks again over TCP
+    let query = client.next_query(Instant::now())?;
+    assert_eq!(server.handle(&query)?.len(), MAX_EDNS_UDP_LEN);
+    let mut retry = query.clone();
+    let size = retry.len() - OPT_LEN + 3;
+    retry[size..size + 2].copy_from_slice(&512u16.to_be_bytes());
+    let response = server.handle(&retry)?;
+    let mut packet = Packet::new(true);
+    packet.deserialize(&response)?;
+    assert!(packet.is_truncated());
+    let error = client.handle_response(&response).unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&ClientError::Truncated));
+    assert!(client.handle_response(&server.handle_tcp(&query)?)?);
+
+    // Queries over TCP get responses as long as the data needs
+    client.set_tcp(true);
+    let mut received = None;
+    for _ in 0..10 {
+        let query = client.next_query(Instant::now())?;
+        let response = server.handle_tcp(&query)?;
+        assert!(response.len() <= crate::tcp::MAX_TCP_LEN);
+        client.handle_response(&response)?;
+        received = received.or_else(|| client.stream().read_message());
+        if received.is_some() {
+            assert!(response.len() > MAX_EDNS_UDP_LEN);
+            break;
+        }
+    }
+    assert_eq!(received, Some(long));
+
+    Ok(())
+}
+
+#[test]
+fn check_probing() -> Result<(), Box<dyn error::Error>> {
+    use crate::dns_packet::{RecordType, OPT_LEN};
+
+    let (mut server, mut client, key) = test_setup()?;
+    // The path loses NULL answers and mangles TXT ones, so MX is the best
+    // type left
+    while client.session().is_none() {
+        let query = client.next_query(Instant::now())?;
+        let mut request = Packet::new(false);
+        request.deserialize(&query)?;
+        let mut response = server.handle(&query)?;
+        match request.query_type() {
+            Some(RecordType::NULL) => continue,
+            // Last byte of the data, before the OPT record
+            Some(RecordType::TXT) => {
+                let len = response.len();
+                response[len - OPT_LEN - 1] ^= 1;
+            }
+            _ => (),
+        }
+        client.handle_response(&response)?;
+    }
+    assert_eq!(client.record_type(), RecordType::MX);
+    // Probes don't open sessions
+    assert_eq!(server.sessions.len(), 1);
+
+    // Nothing making it through leaves A records to try
+    let mut client = crate::client::Client::new(server.domain.clone(), key.clone());
+    for _ in 0..7 {
+        client.next_query(Instant::now())?;
+    }
+    assert_eq!(client.record_type(), RecordType::A);
+
+    Ok(())
+}
+
+#[test]
+fn check_polling() -> Result<(), Box<dyn error::Error>> {
+    let (mut server, mut client, _) = test_setup()?;
+    client.set_record_type(crate::dns_packet::RecordType::TXT);
+
+    // Polls the server has nothing for back off
+    run_exchange(&mut server, &mut client);
+    let id = client.session().ok_or("no session")?;
+    run_exchange(&mut server, &mut client);
+    let delay = client.poll_delay();
+    run_exchange(&mut server, &mut client);
+    run_exchange(&mut server, &mut client);
+    let quiet_delay = client.poll_delay();
+    assert!(quiet_delay > delay);
+
+    // Data queued on the server goes out with the next poll, longer data with
+    // the ones after, and the client picks up the pace again
+    server
+        .stream(id)
+        .ok_or("no stream")?
+        .write_message(b"pushed");
+    run_exchange(&mut server, &mut client);
+    assert_eq!(
+        client.stream().read_message().as_deref(),
+        Some(&b"pushed"[..])
+    );
+    assert!(client.poll_delay() < quiet_delay);
+    let long: Vec<u8> = (0..=255).cycle().take(2000).collect();
+    server.stream(id).ok_or("no stream")?.write_message(&long);
+    let mut received = None;
+    for _ in 0..100 {
+        run_exchange(&mut server, &mut client);
+        received = received.or_else(|| client.stream().read_message());
+    }
+    assert_eq!(received, Some(long));
+
+    Ok(())
+}
+
+#[test]
+fn check_other_types() -> Result<(), Box<dyn error::Error>> {
+    let (mut server, mut client, _) = test_setup()?;
+    // Query for `name` with the type and class given
+    let query = |name: &str, qtype: u16, qclass: u16| {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&qclass.to_be_bytes());
+        query
+    };
+    // Counts of answer and authority records
+    let counts = |response: &[u8]| {
+        (
+            u16::from_be_bytes([response[6], response[7]]),
+            u16::from_be_bytes([response[8], response[9]]),
+        )
+    };
+
+    // HTTPS queries, as resolvers send for any name, get an empty answer with
+    // the SOA, whether at the apex or under it
+    for name in ["t.example.org", "www.t.example.org"] {
+        let response = server.handle(&query(name, 65, 1))?;
+        let mut reply = Packet::new(false);
+        reply.deserialize(&response)?;
+        assert_eq!(reply.rcode(), ResponseCode::NoError);
+        assert_eq!(counts(&response), (0, 1));
+        // The question is echoed as it came
+        let question = query(name, 65, 1);
+        assert_eq!(response[12..question.len()], question[12..]);
+    }
+    // Nothing in the zone is in other classes
+    let mut reply = Packet::new(false);
+    reply.deserialize(&server.handle(&query("t.example.org", 6, 3))?)?;
+    assert_eq!(reply.rcode(), ResponseCode::Refused);
+
+    // Other types for names carrying data leave the data alone, the query
+    // asking for it properly isn't taken for a replay
+    assert!(run_echo(&mut server, &mut client, b"hi").is_some());
+    let query = client.next_query(Instant::now())?;
+    let mut request = Packet::new(false);
+    request.deserialize(&query)?;
+    request.set_query_type(crate::dns_packet::RecordType::Unknown(65));
+    let response = server.handle(request.serialize(1)?.as_raw_slice())?;
+    assert_eq!(counts(&response), (0, 1));
+    assert!(client.handle_response(&server.handle(&query)?)?);
+    assert_eq!(server.replays(), 0);
+    Ok(())
+}
@@ -0,0 +1,60 @@
+// Every query starts with the ID of the session it belongs to, in the clear so
+// that the server can find the session before decrypting anything. ID 0 asks
+// for a new session: the client sends a nonce of its own choosing and its
+// ephemeral public key, and the server answers with that nonce, the ID of the
+// new session and its own ephemeral public key. The nonce lets the server
+// recognize retries of the same request. These are encrypted with the
+// pre-shared key, everything else in the session with the keys derived from
+// the exchange (see handshake.rs).
+//
+// Every message is encrypted along with the ID of its session and the
+// direction it goes in, so that it only decrypts as what it was sent as, and
+// with its number (see replay.rs) in the nonce.
+//
+// ID 0xFFFF probes whether answers of the query's record type make it back
+// intact: the server answers with the client's nonce followed by the probe
+// pattern, filling the response. No session is involved.
+
+pub const ID_LEN: usize = 2;
+pub const OPEN_ID: u16 = 0;
+pub const PROBE_ID: u16 = 0xFFFF;
+pub const NONCE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // From client to server
+    Upstream,
+    Downstream,
+}
+
+// Associated data of the messages of session `id` going in `direction`
+pub fn associated_data(id: u16, direction: Direction) -> [u8; ID_LEN + 1] {
+    let [high, low] = id.to_be_bytes();
+    [high, low, direction as u8]
+}
+
+pub fn prefix_id(id: u16, payload: &[u8]) -> Vec<u8> {
+    let mut data = id.to_be_bytes().to_vec();
+    data.extend_from_slice(payload);
+    data
+}
+
+pub fn split_id(data: &[u8]) -> Option<(u16, &[u8])> {
+    let (id, payload) = data.split_first_chunk::<ID_LEN>()?;
+    Some((u16::from_be_bytes(*id), payload))
+}
+
+// Known data to fill a probe response with, every byte value in turn
+pub fn probe_pattern(len: usize) -> Vec<u8> {
+    (0..=255).cycle().take(len).collect()
+}
+
+// Tests
+
+#[test]
+fn check_session_id() {
+    let data = prefix_id(0x1234, &[5, 6]);
+    assert_eq!(data, [0x12, 0x34, 5, 6]);
+    assert_eq!(split_id(&data), Some((0x1234, &[5u8, 6][..])));
+    assert_eq!(split_id(&[1]), None);
+}
@@ -0,0 +1,250 @@
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// SOCKS5 (RFC 1928) handshake for the client's local proxy. Only CONNECT is
+// supported, without authentication or with username and password (RFC 1929).
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+// Version of the username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+// Reply codes
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SocksError {
+    BadVersion(u8),
+    NoAcceptableMethod,
+    AuthFailed,
+    UnsupportedCommand(u8),
+    UnsupportedAddress(u8),
+    BadDomain,
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocksError::BadVersion(version) => write!(f, "Unsupported SOCKS version {}", version),
+            SocksError::NoAcceptableMethod => write!(f, "No acceptable authentication method"),
+            SocksError::AuthFailed => write!(f, "Wrong username or password"),
+            SocksError::UnsupportedCommand(command) => {
+                write!(f, "Unsupported SOCKS command {}", command)
+            }
+            SocksError::UnsupportedAddress(kind) => write!(f, "Unsupported address type {}", kind),
+            SocksError::BadDomain => write!(f, "Domain name is not valid UTF-8"),
+        }
+    }
+}
+
+impl error::Error for SocksError {}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// Negotiate with a SOCKS client up to its CONNECT request, and return the host
+// and port it wants. The client waits for `reply` before sending any data.
+pub fn handshake<S: Read + Write>(
+    socket: &mut S,
+    credentials: Option<&Credentials>,
+) -> Result<(String, u16), Box<dyn error::Error>> {
+    let [version, method_count] = read_array(socket)?;
+    if version != VERSION {
+        return Err(SocksError::BadVersion(version).into());
+    }
+    let mut methods = vec![0u8; method_count as usize];
+    socket.read_exact(&mut methods)?;
+    let method = if credentials.is_some() {
+        USER_PASS
+    } else {
+        NO_AUTH
+    };
+    if !methods.contains(&method) {
+        socket.write_all(&[VERSION, NO_ACCEPTABLE_METHOD])?;
+        return Err(SocksError::NoAcceptableMethod.into());
+    }
+    socket.write_all(&[VERSION, method])?;
+    if let Some(credentials) = credentials {
+        authenticate(socket, credentials)?;
+    }
+
+    let [version, command, _, address_type] = read_array(socket)?;
+    if version != VERSION {
+        return Err(SocksError::BadVersion(version).into());
+    }
+    let host = match address_type {
+        IPV4 => Ipv4Addr::from(read_array::<4>(socket)?).to_string(),
+        IPV6 => Ipv6Addr::from(read_array::<16>(socket)?).to_string(),
+        DOMAIN => {
+            let [len] = read_array(socket)?;
+            let mut name = vec![0u8; len as usize];
+            socket.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| SocksError::BadDomain)?
+        }
+        _ => {
+            send_reply(socket, ADDRESS_NOT_SUPPORTED)?;
+            return Err(SocksError::UnsupportedAddress(address_type).into());
+        }
+    };
+    let port = u16::from_be_bytes(read_array(socket)?);
+    if command != CONNECT {
+        send_reply(socket, COMMAND_NOT_SUPPORTED)?;
+        return Err(SocksError::UnsupportedCommand(command).into());
+    }
+    Ok((host, port))
+}
+
+// Tell the client whether the connection it asked for was made
+pub fn reply<W: Write>(socket: &mut W, connected: bool) -> io::Result<()> {
+    let code = if connected {
+        SUCCEEDED
+    } else {
+        GENERAL_FAILURE
+    };
+    send_reply(socket, code)
+}
+
+fn authenticate<S: Read + Write>(
+    socket: &mut S,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn error::Error>> {
+    let [version, len] = read_array(socket)?;
+    if version != AUTH_VERSION {
+        return Err(SocksError::BadVersion(version).into());
+    }
+    let mut username = vec![0u8; len as usize];
+    socket.read_exact(&mut username)?;
+    let [len] = read_array(socket)?;
+    let mut password = vec![0u8; len as usize];
+    socket.read_exact(&mut password)?;
+
+    if username != credentials.username.as_bytes() || password != credentials.password.as_bytes() {
+        socket.write_all(&[AUTH_VERSION, 1])?;
+        return Err(SocksError::AuthFailed.into());
+    }
+    socket.write_all(&[AUTH_VERSION, 0])?;
+    Ok(())
+}
+
+fn send_reply<W: Write>(socket: &mut W, code: u8) -> io::Result<()> {
+    // The address the server connected from is of no use on this side of the
+    // tunnel, so it's left unspecified
+    socket.write_all(&[VERSION, code, 0, IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+fn read_array<const N: usize>(socket: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    socket.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Tests
+
+#[cfg(test)]
+struct TestSocket {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Read for TestSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for TestSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn run_handshake(
+    input: &[u8],
+    credentials: Option<&Credentials>,
+) -> (Result<(String, u16), SocksError>, Vec<u8>) {
+    let mut socket = TestSocket {
+        input: io::Cursor::new(input.to_vec()),
+        output: Vec::new(),
+    };
+    let result = handshake(&mut socket, credentials)
+        .map_err(|e| *e.downcast::<SocksError>().expect("not a SOCKS error"));
+    (result, socket.output)
+}
+
+#[test]
+fn check_handshake() {
+    // CONNECT to a domain name, and to IP addresses
+    let (target, output) =
+        run_handshake(b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.org\x00\x50", None);
+    assert_eq!(target, Ok(("example.org".to_string(), 80)));
+    assert_eq!(output, [5, 0]);
+    let (target, _) = run_handshake(
+        b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x01\xbb",
+        None,
+    );
+    assert_eq!(target, Ok(("127.0.0.1".to_string(), 443)));
+    let mut request = b"\x05\x01\x00\x05\x01\x00\x04".to_vec();
+    request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    request.extend_from_slice(&[0, 22]);
+    let (target, _) = run_handshake(&request, None);
+    assert_eq!(target, Ok(("::1".to_string(), 22)));
+
+    // Other commands are refused with a reply
+    let (target, output) = run_handshake(
+        b"\x05\x01\x00\x05\x02\x00\x01\x7f\x00\x00\x01\x01\xbb",
+        None,
+    );
+    assert_eq!(target, Err(SocksError::UnsupportedCommand(2)));
+    assert_eq!(
+        output,
+        [5, 0, 5, COMMAND_NOT_SUPPORTED, 0, 1, 0, 0, 0, 0, 0, 0]
+    );
+    let (target, _) = run_handshake(b"\x04\x01\x00\x50", None);
+    assert_eq!(target, Err(SocksError::BadVersion(4)));
+}
+
+#[test]
+fn check_authentication() {
+    let credentials = Credentials {
+        username: "user".into(),
+        password: "secret".into(),
+    };
+    let (target, output) = run_handshake(
+        b"\x05\x02\x00\x02\x01\x04user\x06secret\x05\x01\x00\x03\x01a\x00\x50",
+        Some(&credentials),
+    );
+    assert_eq!(target, Ok(("a".to_string(), 80)));
+    assert_eq!(output, [5, 2, 1, 0]);
+
+    let (target, output) =
+        run_handshake(b"\x05\x02\x00\x02\x01\x04user\x05wrong", Some(&credentials));
+    assert_eq!(target, Err(SocksError::AuthFailed));
+    assert_eq!(output, [5, 2, 1, 1]);
+
+    // Clients which can't authenticate are turned away
+    let (target, output) = run_handshake(b"\x05\x01\x00", Some(&credentials));
+    assert_eq!(target, Err(SocksError::NoAcceptableMethod));
+    assert_eq!(output, [5, NO_ACCEPTABLE_METHOD]);
+}
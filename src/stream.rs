@@ -0,0 +1,493 @@
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Bytes sent but not acknowledged yet, and how far ahead of what was read
+// data is accepted
+pub const WINDOW: u32 = 32 * 1024;
+// Retransmission timeout, doubled for every retry of the same segment
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StreamError {
+    // Length needed and length available
+    Truncated(usize, usize),
+    // Acknowledgement for data which was never sent
+    InvalidAck(u32),
+    // Segment too far ahead of what was delivered so far
+    OutOfWindow(u32),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Truncated(needed, len) => {
+                write!(f, "Segment truncated ({}/{})", len, needed)
+            }
+            StreamError::InvalidAck(ack) => write!(f, "Acknowledgement {} for unsent data", ack),
+            StreamError::OutOfWindow(seq) => write!(f, "Segment {} outside window", seq),
+        }
+    }
+}
+
+impl error::Error for StreamError {}
+
+// Piece of a stream carried by a single DNS query or response. Sequence
+// numbers count the bytes of the stream, and the end of the stream takes one
+// after the last byte, so that a segment can be sent again in smaller pieces.
+// Segments carrying neither only bring the acknowledgement across, with the
+// next sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    // Position of the first byte of data in the stream
+    pub seq: u32,
+    // Sequence number of the next byte expected from the peer
+    pub ack: u32,
+    // Last segment of the stream
+    pub fin: bool,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    // seq, ack and flags
+    pub const HEADER_LEN: usize = 9;
+    const FLAG_FIN: u8 = 0x01;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::HEADER_LEN + self.data.len());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.ack.to_be_bytes());
+        buf.push(if self.fin { Self::FLAG_FIN } else { 0 });
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, StreamError> {
+        if buf.len() < Self::HEADER_LEN {
+            return Err(StreamError::Truncated(Self::HEADER_LEN, buf.len()));
+        }
+        Ok(Segment {
+            seq: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            ack: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            fin: buf[8] & Self::FLAG_FIN != 0,
+            data: buf[Self::HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+struct Sent {
+    seq: u32,
+    fin: bool,
+    data: Vec<u8>,
+    deadline: Instant,
+    retries: u32,
+}
+
+impl Sent {
+    // Sequence number following the segment
+    fn end(&self) -> u32 {
+        segment_end(self.seq, &self.data, self.fin)
+    }
+}
+
+fn segment_end(seq: u32, data: &[u8], fin: bool) -> u32 {
+    seq.wrapping_add(data.len() as u32).wrapping_add(fin as u32)
+}
+
+// Whether sequence number `a` comes before `b`
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+// One end of a reliable byte stream over lossy request/response exchanges.
+// Sent segments are kept until acknowledged and sent again once their timer
+// runs out, received ones are delivered in order and exactly once.
+#[derive(Default)]
+pub struct Stream {
+    send_buf: VecDeque<u8>,
+    unacked: VecDeque<Sent>,
+    next_seq: u32,
+    closing: bool,
+    fin_sent: bool,
+
+    expected: u32,
+    out_of_order: HashMap<u32, (Vec<u8>, bool)>,
+    recv_buf: Vec<u8>,
+    peer_closed: bool,
+    // Something arrived which the peer should hear about
+    needs_ack: bool,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.send_buf.extend(data);
+    }
+
+    // Number of bytes written and not put in a segment yet
+    pub fn buffered(&self) -> usize {
+        self.send_buf.len()
+    }
+
+    // Take everything delivered so far
+    pub fn read(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.recv_buf)
+    }
+
+    // Messages are sent on the stream with a 4 byte length in front
+    pub fn write_message(&mut self, message: &[u8]) {
+        self.write(&(message.len() as u32).to_be_bytes());
+        self.write(message);
+    }
+
+    // Take the next message once it's delivered in full
+    pub fn read_message(&mut self) -> Option<Vec<u8>> {
+        let len = u32::from_be_bytes(self.recv_buf.get(..4)?.try_into().unwrap()) as usize;
+        let message = self.recv_buf.get(4..4 + len)?.to_vec();
+        self.recv_buf.drain(..4 + len);
+        Some(message)
+    }
+
+    // End the stream after the data written so far. Nothing may be written
+    // after this.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    // The peer ended the stream, and everything it sent was delivered
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    // Both ends ended the stream, and know the other one knows
+    pub fn is_closed(&self) -> bool {
+        self.fin_sent && self.unacked.is_empty() && self.peer_closed && !self.needs_ack
+    }
+
+    // Nothing waiting to be sent or acknowledged
+    pub fn is_idle(&self) -> bool {
+        !self.has_unsent() && self.unacked.is_empty()
+    }
+
+    // Data written, or the end of the stream, not put in a segment yet
+    pub fn has_unsent(&self) -> bool {
+        !self.send_buf.is_empty() || self.closing != self.fin_sent
+    }
+
+    pub fn receive(&mut self, segment: Segment) -> Result<(), StreamError> {
+        // Acknowledgements are cumulative, and may be outdated if exchanges
+        // got reordered on the way
+        if before(self.next_seq, segment.ack) {
+            return Err(StreamError::InvalidAck(segment.ack));
+        }
+        while let Some(oldest) = self.unacked.front_mut() {
+            if !before(oldest.seq, segment.ack) {
+                break;
+            }
+            if before(segment.ack, oldest.end()) {
+                // Only the first part of a segment sent in pieces arrived
+                let acked = segment.ack.wrapping_sub(oldest.seq) as usize;
+                oldest.data.drain(..acked);
+                oldest.seq = segment.ack;
+                break;
+            }
+            self.unacked.pop_front();
+        }
+
+        if segment.data.is_empty() && !segment.fin {
+            return Ok(());
+        }
+        if !before(
+            self.expected,
+            segment_end(segment.seq, &segment.data, segment.fin),
+        ) {
+            // Already delivered, our acknowledgement must have been lost
+            self.needs_ack = true;
+            return Ok(());
+        }
+        if !before(segment.seq, self.expected.wrapping_add(WINDOW)) {
+            return Err(StreamError::OutOfWindow(segment.seq));
+        }
+        // Data delivered but not read yet takes up the window, the peer sends
+        // what doesn't fit again later
+        let window = WINDOW.saturating_sub(self.recv_buf.len() as u32);
+        if !before(segment.seq, self.expected.wrapping_add(window)) {
+            return Ok(());
+        }
+        self.needs_ack = true;
+        // Pieces of a segment may overlap with the whole of it, the longest
+        // one starting at a position is kept
+        let entry = self
+            .out_of_order
+            .entry(segment.seq)
+            .or_insert((Vec::new(), false));
+        if segment.data.len() + segment.fin as usize > entry.0.len() + entry.1 as usize {
+            *entry = (segment.data, segment.fin);
+        }
+        while let Some(seq) = self.next_deliverable() {
+            let (data, fin) = self.out_of_order.remove(&seq).unwrap();
+            let skip = self.expected.wrapping_sub(seq) as usize;
+            self.recv_buf
+                .extend_from_slice(&data[skip.min(data.len())..]);
+            self.peer_closed |= fin;
+            self.expected = segment_end(seq, &data, fin);
+        }
+        let expected = self.expected;
+        self.out_of_order
+            .retain(|&seq, (data, fin)| before(expected, segment_end(seq, data, *fin)));
+        Ok(())
+    }
+
+    // Start of the data received which continues what was delivered so far
+    fn next_deliverable(&self) -> Option<u32> {
+        self.out_of_order
+            .iter()
+            .find(|(&seq, (data, fin))| {
+                !before(self.expected, seq) && before(self.expected, segment_end(seq, data, *fin))
+            })
+            .map(|(&seq, _)| seq)
+    }
+
+    // Segment to send now, carrying at most `max_data` bytes. Retransmissions
+    // go first, then new data, and otherwise the segment is just an ack.
+    // Segments sent while there was more room go again in pieces.
+    pub fn next_segment(&mut self, max_data: usize, now: Instant) -> Segment {
+        let ack = self.expected;
+        self.needs_ack = false;
+        let expired = self
+            .unacked
+            .iter()
+            .position(|s| s.deadline <= now && (max_data > 0 || s.data.is_empty()));
+        if let Some(index) = expired {
+            if self.unacked[index].data.len() > max_data {
+                let sent = &mut self.unacked[index];
+                let rest = Sent {
+                    seq: sent.seq.wrapping_add(max_data as u32),
+                    fin: sent.fin,
+                    data: sent.data.split_off(max_data),
+                    deadline: sent.deadline,
+                    retries: sent.retries,
+                };
+                sent.fin = false;
+                self.unacked.insert(index + 1, rest);
+            }
+            let sent = &mut self.unacked[index];
+            sent.retries += 1;
+            sent.deadline = now + (INITIAL_RTO * 2u32.pow(sent.retries.min(4))).min(MAX_RTO);
+            return Segment {
+                seq: sent.seq,
+                ack,
+                fin: sent.fin,
+                data: sent.data.clone(),
+            };
+        }
+
+        let seq = self.next_seq;
+        let in_flight = self
+            .unacked
+            .front()
+            .map_or(0, |oldest| seq.wrapping_sub(oldest.seq));
+        let len = max_data
+            .min(self.send_buf.len())
+            .min(WINDOW.saturating_sub(in_flight) as usize);
+        // The end of the stream goes with the last data, or on its own
+        let fin = self.closing && !self.fin_sent && len == self.send_buf.len();
+        if (len == 0 && !fin) || in_flight >= WINDOW {
+            return Segment {
+                seq,
+                ack,
+                fin: false,
+                data: Vec::new(),
+            };
+        }
+        let data: Vec<u8> = self.send_buf.drain(..len).collect();
+        self.fin_sent |= fin;
+        self.next_seq = segment_end(seq, &data, fin);
+        self.unacked.push_back(Sent {
+            seq,
+            fin,
+            data: data.clone(),
+            deadline: now + INITIAL_RTO,
+            retries: 0,
+        });
+        Segment {
+            seq,
+            ack,
+            fin,
+            data,
+        }
+    }
+}
+
+// Tests
+
+#[test]
+fn check_segment_roundtrip() -> Result<(), Box<dyn error::Error>> {
+    let segment = Segment {
+        seq: 0x1234,
+        ack: 0xFFFF_FFFF,
+        fin: true,
+        data: vec![1, 2, 3],
+    };
+    let buf = segment.serialize();
+    assert_eq!(buf, [0, 0, 0x12, 0x34, 0xFF, 0xFF, 0xFF, 0xFF, 1, 1, 2, 3]);
+    assert_eq!(Segment::deserialize(&buf)?, segment);
+    assert_eq!(
+        Segment::deserialize(&buf[..8]),
+        Err(StreamError::Truncated(9, 8))
+    );
+    Ok(())
+}
+
+#[test]
+fn check_lossy_delivery() -> Result<(), Box<dyn error::Error>> {
+    let message: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let mut client = Stream::new();
+    let mut server = Stream::new();
+    client.write_message(&message);
+    client.close();
+    server.write_message(b"reply");
+    server.close();
+
+    // Every third query and every fourth response is lost, and every
+    // response gets delivered twice
+    let mut now = Instant::now();
+    let mut received = None;
+    let mut reply = None;
+    for exchange in 0..1000 {
+        now += Duration::from_millis(100);
+        let query = client.next_segment(20, now);
+        assert!(query.data.len() <= 20);
+        if exchange % 3 == 2 {
+            continue;
+        }
+        server.receive(query)?;
+        let response = server.next_segment(10, now);
+        if exchange % 4 == 3 {
+            continue;
+        }
+        client.receive(response.clone())?;
+        client.receive(response)?;
+
+        received = received.or_else(|| server.read_message());
+        reply = reply.or_else(|| client.read_message());
+        if client.is_closed() && server.is_closed() {
+            break;
+        }
+    }
+    assert_eq!(received, Some(message));
+    assert_eq!(reply.as_deref(), Some(&b"reply"[..]));
+    assert!(client.is_closed() && server.is_closed());
+    assert!(server.read().is_empty());
+
+    // Segments far ahead, or acknowledging unsent data, are refused
+    let segment = Segment {
+        seq: server.expected.wrapping_add(WINDOW),
+        ack: server.next_seq,
+        fin: false,
+        data: vec![1],
+    };
+    assert_eq!(
+        server.receive(segment.clone()),
+        Err(StreamError::OutOfWindow(segment.seq))
+    );
+    let segment = Segment {
+        ack: server.next_seq.wrapping_add(1),
+        data: Vec::new(),
+        ..segment
+    };
+    assert_eq!(
+        server.receive(segment.clone()),
+        Err(StreamError::InvalidAck(segment.ack))
+    );
+    Ok(())
+}
+
+#[test]
+fn check_shrinking_room() -> Result<(), Box<dyn error::Error>> {
+    let message: Vec<u8> = (0..=255).cycle().take(300).collect();
+    let mut server = Stream::new();
+    let mut client = Stream::new();
+    server.write(&message);
+    server.close();
+
+    // Responses first have room for 100 bytes and get lost, the retries only
+    // have room for 30
+    let mut now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(server.next_segment(100, now).data.len(), 100);
+    }
+    for _ in 0..100 {
+        now += MAX_RTO;
+        let segment = server.next_segment(30, now);
+        assert!(segment.data.len() <= 30);
+        client.receive(segment)?;
+        server.receive(client.next_segment(30, now))?;
+        if server.is_idle() {
+            break;
+        }
+    }
+    assert!(server.is_idle());
+    assert!(client.is_peer_closed());
+    assert_eq!(client.read(), message);
+
+    // Pieces overlapping what arrived whole before are taken as far as
+    // they're new
+    let mut server = Stream::new();
+    let mut client = Stream::new();
+    server.write(&message);
+    let whole = server.next_segment(100, now);
+    let next = server.next_segment(100, now);
+    client.receive(whole.clone())?;
+    let piece = server.next_segment(40, now + MAX_RTO);
+    assert_eq!(
+        (piece.seq, piece.data.as_slice()),
+        (whole.seq, &whole.data[..40])
+    );
+    client.receive(piece)?;
+    client.receive(Segment {
+        seq: whole.seq + 50,
+        data: message[50..150].to_vec(),
+        ..next.clone()
+    })?;
+    client.receive(next)?;
+    assert_eq!(client.read(), message[..200]);
+    assert!(client.out_of_order.is_empty());
+    server.receive(client.next_segment(0, now))?;
+    assert_eq!(server.unacked.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn check_receive_window() -> Result<(), Box<dyn error::Error>> {
+    let message: Vec<u8> = (0..=255).cycle().take(WINDOW as usize * 2).collect();
+    let mut client = Stream::new();
+    let mut server = Stream::new();
+    client.write(&message);
+
+    // Data nobody reads fills the window, and what comes after it waits
+    let now = Instant::now();
+    for _ in 0..100 {
+        server.receive(client.next_segment(1000, now))?;
+        client.receive(server.next_segment(1000, now))?;
+    }
+    // The last segment taken started within it
+    let held = server.recv_buf.len();
+    assert!(held >= WINDOW as usize && held < WINDOW as usize + 1000);
+    assert!(server.out_of_order.is_empty());
+    let mut received = server.read();
+    for exchange in 1..200 {
+        let now = now + MAX_RTO * exchange;
+        server.receive(client.next_segment(1000, now))?;
+        client.receive(server.next_segment(1000, now))?;
+        received.extend(server.read());
+    }
+    assert_eq!(received, message);
+    assert!(client.is_idle());
+    Ok(())
+}
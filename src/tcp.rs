@@ -0,0 +1,60 @@
+use std::io::{self, Read, Write};
+
+// DNS over TCP (RFC 1035 4.2.2, RFC 7766): every message goes with its length
+// in front, in 2 bytes. A connection carries any number of queries, and the
+// client may send more before the responses to earlier ones arrived.
+
+// Largest message the length prefix allows
+pub const MAX_TCP_LEN: usize = u16::MAX as usize;
+
+pub fn write_message<W: Write>(stream: &mut W, msg: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+    let mut buf = Vec::with_capacity(2 + msg.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(msg);
+    // One write, so that the message doesn't go out in two segments
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+// Next message on the connection, or None once the other side closed it
+// between messages
+pub fn read_message<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg)?;
+    Ok(Some(msg))
+}
+
+// Tests
+
+#[test]
+fn check_framing() -> io::Result<()> {
+    // Pipelined messages arrive back to back
+    let mut buf = Vec::new();
+    write_message(&mut buf, b"first")?;
+    write_message(&mut buf, b"")?;
+    write_message(&mut buf, &[7; 300])?;
+    assert_eq!(buf[..7], [0, 5, b'f', b'i', b'r', b's', b't']);
+    assert_eq!(buf[9..11], [1, 44]);
+
+    let mut stream = io::Cursor::new(buf.clone());
+    assert_eq!(read_message(&mut stream)?.as_deref(), Some(&b"first"[..]));
+    assert_eq!(read_message(&mut stream)?.as_deref(), Some(&b""[..]));
+    assert_eq!(read_message(&mut stream)?, Some(vec![7; 300]));
+    assert_eq!(read_message(&mut stream)?, None);
+
+    // Connections closed in the middle of a message are an error
+    let mut stream = io::Cursor::new(buf[..20].to_vec());
+    read_message(&mut stream)?;
+    read_message(&mut stream)?;
+    assert!(read_message(&mut stream).is_err());
+    assert!(write_message(&mut Vec::new(), &vec![0; MAX_TCP_LEN + 1]).is_err());
+    Ok(())
+}
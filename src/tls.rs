@@ -0,0 +1,232 @@
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use data_encoding::BASE64;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, ring, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+// TLS settings for the encrypted transports. Certificates and keys are read
+// from PEM files, and servers are checked against the usual web roots unless
+// the client is given the CA to trust, e.g. a self-signed server certificate,
+// or pins of the keys the server may have.
+
+// DNS over TLS (RFC 7858) goes to its own port, and says so in the handshake
+pub const DOT_PORT: u16 = 853;
+pub const DOT_ALPN: &[u8] = b"dot";
+
+#[derive(Debug)]
+pub enum TlsError {
+    // File which couldn't be read or parsed
+    Pem(PathBuf, pem::Error),
+    NoCertificate(PathBuf),
+    InvalidName(String),
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Pem(path, e) => write!(f, "Error reading {}: {}", path.display(), e),
+            TlsError::NoCertificate(path) => write!(f, "No certificate in {}", path.display()),
+            TlsError::InvalidName(name) => write!(f, "Invalid server name {}", name),
+            TlsError::Rustls(e) => write!(f, "TLS error: {}", e),
+        }
+    }
+}
+
+impl error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+// Pin of a certificate's public key, the SHA-256 digest of its
+// SubjectPublicKeyInfo (RFC 7469), written sha256/<base64>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    const PREFIX: &'static str = "sha256/";
+
+    pub fn of(cert: &CertificateDer) -> Result<Self, TlsError> {
+        let cert = webpki::EndEntityCert::try_from(cert)
+            .map_err(|e| TlsError::Rustls(rustls::Error::General(e.to_string())))?;
+        Ok(Pin(Sha256::digest(cert.subject_public_key_info()).into()))
+    }
+
+    // Pin of the first certificate in a PEM file
+    pub fn of_file(path: &Path) -> Result<Self, TlsError> {
+        Pin::of(&read_certificates(path)?[0])
+    }
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digest = s.strip_prefix(Self::PREFIX).unwrap_or(s);
+        BASE64
+            .decode(digest.as_bytes())
+            .ok()
+            .and_then(|digest| digest.try_into().ok())
+            .map(Pin)
+            .ok_or_else(|| {
+                format!(
+                    "expected {}<base64 SHA-256 digest>, got {}",
+                    Self::PREFIX,
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", Self::PREFIX, BASE64.encode(&self.0))
+    }
+}
+
+// Accepts servers presenting a certificate for one of the pinned keys,
+// whoever signed it and whatever name it's for
+#[derive(Debug)]
+struct PinVerifier {
+    pins: Vec<Pin>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pin = Pin::of(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if !self.pins.contains(&pin) {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+// Client settings for the application protocol `alpn`, trusting servers with
+// a pinned key if there are any pins, or else certificates signed by those in
+// `ca_cert` or by the web roots
+pub fn client_config(
+    ca_cert: Option<&Path>,
+    pins: &[Pin],
+    alpn: &[u8],
+) -> Result<Arc<ClientConfig>, TlsError> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let mut config = if !pins.is_empty() {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier {
+                pins: pins.to_vec(),
+                algorithms: provider.signature_verification_algorithms,
+            }))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        match ca_cert {
+            Some(path) => {
+                for cert in read_certificates(path)? {
+                    roots.add(cert)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Server settings presenting the certificate chain in `cert`, which the key
+// in `key` belongs to. Clients asking for an application protocol must ask
+// for `alpn`.
+pub fn server_config(cert: &Path, key: &Path, alpn: &[u8]) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = read_certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| TlsError::Pem(key.to_path_buf(), e))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+pub fn server_name(host: &str) -> Result<ServerName<'static>, TlsError> {
+    ServerName::try_from(host.to_string()).map_err(|_| TlsError::InvalidName(host.to_string()))
+}
+
+// Tests
+
+#[test]
+fn check_pins() -> Result<(), Box<dyn error::Error>> {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let pin = Pin::of(cert.der())?;
+    // Certificates for the same key have the same pin
+    let params = rcgen::CertificateParams::new(vec!["other.example".to_string()])?;
+    assert_eq!(Pin::of(params.self_signed(&key_pair)?.der())?, pin);
+    let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    assert_ne!(Pin::of(other.cert.der())?, pin);
+
+    assert_eq!(pin.to_string().parse::<Pin>(), Ok(pin));
+    assert_eq!(pin.to_string()[7..].parse::<Pin>(), Ok(pin));
+    assert!("sha256/AAAA".parse::<Pin>().is_err());
+    assert!("sha256/not base64".parse::<Pin>().is_err());
+    Ok(())
+}
@@ -0,0 +1,138 @@
+// End-to-end test of the tunnel over DNS over HTTPS, against a server with a
+// self-signed certificate which the client is told to trust.
+
+use std::fs;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+
+use dns_camo::client::Client;
+use dns_camo::dns_packet::DnsName;
+use dns_camo::doh::{self, DohError, DohUrl, Method};
+use dns_camo::keys::{Key, Keyring};
+use dns_camo::server::Server;
+use dns_camo::tls;
+
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dns-camo-{}-{}", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+// Certificate for localhost, and the files it and its key are in
+fn certificate(name: &str) -> (PathBuf, PathBuf) {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (
+        temp_file(&format!("{}-cert", name), cert.pem().as_bytes()),
+        temp_file(
+            &format!("{}-key", name),
+            key_pair.serialize_pem().as_bytes(),
+        ),
+    )
+}
+
+// Server answering DoH requests one connection at a time, and echoing every
+// message back
+fn spawn_server(key: PathBuf, config: Arc<ServerConfig>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(
+        DnsName::from_str("t.example.org").unwrap(),
+        DnsName::from_str("ns.example.org").unwrap(),
+        Keyring::single(Key::from_file(&key).unwrap()),
+    );
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            let tls = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = BufReader::new(StreamOwned::new(tls, connection.unwrap()));
+            loop {
+                let query = match doh::read_query(&mut stream, doh::DEFAULT_PATH) {
+                    Ok(Some(query)) => query,
+                    Ok(None) => break,
+                    Err(DohError::Status(status)) => {
+                        doh::write_error(stream.get_mut(), status).unwrap();
+                        break;
+                    }
+                    // Clients which don't trust the certificate hang up
+                    Err(_) => break,
+                };
+                let reply = server.handle_tcp(&query).unwrap();
+                doh::write_response(stream.get_mut(), &reply).unwrap();
+                for id in server.session_ids() {
+                    let stream = server.stream(id).unwrap();
+                    while let Some(message) = stream.read_message() {
+                        stream.write_message(&message);
+                    }
+                }
+            }
+        }
+    });
+    addr
+}
+
+type Connection = BufReader<StreamOwned<ClientConnection, TcpStream>>;
+
+fn connect(addr: SocketAddr, config: Arc<ClientConfig>) -> Connection {
+    let tls = ClientConnection::new(config, tls::server_name("localhost").unwrap()).unwrap();
+    BufReader::new(StreamOwned::new(tls, TcpStream::connect(addr).unwrap()))
+}
+
+// Send a message and poll for the reply over one connection
+fn run_client(
+    client: &mut Client,
+    connection: &mut Connection,
+    url: &DohUrl,
+    method: Method,
+    message: &[u8],
+) -> Vec<u8> {
+    client.stream().write_message(message);
+    for _ in 0..100 {
+        if let Some(reply) = client.stream().read_message() {
+            return reply;
+        }
+        let query = client.next_query(Instant::now()).unwrap();
+        doh::write_query(connection.get_mut(), url, method, &query).unwrap();
+        let response = doh::read_response(connection).unwrap();
+        assert!(client.handle_response(&response).unwrap());
+    }
+    panic!("no reply");
+}
+
+#[test]
+fn tunnel_over_https() {
+    let key = temp_file("doh", &[0x42u8; 32]);
+    let (cert, cert_key) = certificate("doh");
+    let server_config = tls::server_config(&cert, &cert_key, doh::ALPN).unwrap();
+    let addr = spawn_server(key.clone(), server_config);
+    let url = DohUrl::from_str(&format!("https://localhost:{}/dns-query", addr.port())).unwrap();
+    let config = tls::client_config(Some(&cert), &[], doh::ALPN).unwrap();
+
+    // Responses carry a lot more than UDP ones would
+    let data: Vec<u8> = (0..=255).cycle().take(5000).collect();
+    for method in [Method::Post, Method::Get] {
+        let mut client = Client::new(
+            DnsName::from_str("t.example.org").unwrap(),
+            Key::from_file(&key).unwrap(),
+        );
+        client.set_record_type(dns_camo::dns_packet::RecordType::TXT);
+        client.set_tcp(true);
+        let mut connection = connect(addr, config.clone());
+        let reply = run_client(&mut client, &mut connection, &url, method, &data);
+        assert_eq!(reply, data, "{:?}", method);
+    }
+
+    // Servers whose certificate isn't trusted are refused
+    let mut connection = connect(addr, tls::client_config(None, &[], doh::ALPN).unwrap());
+    assert!(doh::write_query(connection.get_mut(), &url, Method::Post, b"query").is_err());
+
+    for path in [key, cert, cert_key] {
+        fs::remove_file(path).unwrap();
+    }
+}
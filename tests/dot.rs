@@ -0,0 +1,117 @@
+// End-to-end test of the tunnel over DNS over TLS, with the client trusting
+// the server's self-signed certificate by the pin of its key.
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use rustls::{ClientConfig, ClientConnection, ServerConnection, StreamOwned};
+
+use dns_camo::client::Client;
+use dns_camo::dns_packet::{DnsName, RecordType};
+use dns_camo::keys::{Key, Keyring};
+use dns_camo::server::Server;
+use dns_camo::tcp;
+use dns_camo::tls::{self, Pin};
+
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dns-camo-{}-{}", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+// Server with a self-signed certificate in `cert`, answering DoT queries one
+// connection at a time and echoing every message back
+fn spawn_server(key: PathBuf, cert: &rcgen::CertifiedKey) -> SocketAddr {
+    let cert_file = temp_file("dot-cert", cert.cert.pem().as_bytes());
+    let key_file = temp_file("dot-key", cert.key_pair.serialize_pem().as_bytes());
+    let config = tls::server_config(&cert_file, &key_file, tls::DOT_ALPN).unwrap();
+    fs::remove_file(cert_file).unwrap();
+    fs::remove_file(key_file).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(
+        DnsName::from_str("t.example.org").unwrap(),
+        DnsName::from_str("ns.example.org").unwrap(),
+        Keyring::single(Key::from_file(&key).unwrap()),
+    );
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            let tls = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = StreamOwned::new(tls, connection.unwrap());
+            // Clients which don't like the certificate hang up
+            while let Ok(Some(query)) = tcp::read_message(&mut stream) {
+                let reply = server.handle_tcp(&query).unwrap();
+                tcp::write_message(&mut stream, &reply).unwrap();
+                for id in server.session_ids() {
+                    let stream = server.stream(id).unwrap();
+                    while let Some(message) = stream.read_message() {
+                        stream.write_message(&message);
+                    }
+                }
+            }
+        }
+    });
+    addr
+}
+
+type Connection = StreamOwned<ClientConnection, TcpStream>;
+
+// Connect to the server by a name its certificate isn't for, which only
+// pinning lets through
+fn connect(addr: SocketAddr, config: Arc<ClientConfig>) -> Connection {
+    let name = tls::server_name("dot.example.net").unwrap();
+    let tls = ClientConnection::new(config, name).unwrap();
+    StreamOwned::new(tls, TcpStream::connect(addr).unwrap())
+}
+
+#[test]
+fn tunnel_over_tls() {
+    let key = temp_file("dot", &[0x42u8; 32]);
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let addr = spawn_server(key.clone(), &cert);
+    let pin = Pin::of(cert.cert.der()).unwrap();
+
+    let config = tls::client_config(None, &[pin], tls::DOT_ALPN).unwrap();
+    let mut connection = connect(addr, config);
+    let mut client = Client::new(
+        DnsName::from_str("t.example.org").unwrap(),
+        Key::from_file(&key).unwrap(),
+    );
+    client.set_record_type(RecordType::TXT);
+    client.set_tcp(true);
+    let data: Vec<u8> = (0..=255).cycle().take(5000).collect();
+    client.stream().write_message(&data);
+    let mut reply = None;
+    for _ in 0..100 {
+        reply = client.stream().read_message();
+        if reply.is_some() {
+            break;
+        }
+        let query = client.next_query(Instant::now()).unwrap();
+        tcp::write_message(&mut connection, &query).unwrap();
+        let response = tcp::read_message(&mut connection).unwrap().unwrap();
+        assert!(client.handle_response(&response).unwrap());
+    }
+    assert_eq!(reply, Some(data));
+    // The server answers one connection at a time
+    drop(connection);
+
+    // Other keys, or the usual checks, don't trust the server
+    let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let other_pin = Pin::of(other.cert.der()).unwrap();
+    for config in [
+        tls::client_config(None, &[other_pin], tls::DOT_ALPN).unwrap(),
+        tls::client_config(None, &[], tls::DOT_ALPN).unwrap(),
+    ] {
+        let mut connection = connect(addr, config);
+        assert!(tcp::write_message(&mut connection, b"query").is_err());
+    }
+
+    fs::remove_file(key).unwrap();
+}
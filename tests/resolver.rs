@@ -0,0 +1,363 @@
+// End-to-end test of the tunnel through a stand-in recursive resolver, which
+// behaves like real ones in the ways that matter to us: it picks its own query
+// IDs, only takes one question per query, randomizes the letter case of query
+// names (0x20) and checks the reply echoes them exactly, retries queries, only
+// passes the answer and authority sections on, and loses some replies.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dns_camo::client::Client;
+use dns_camo::dns_packet::{DnsName, Packet, RecordType, ResponseCode, MAX_EDNS_UDP_LEN};
+use dns_camo::keys::{Key, Keyring};
+use dns_camo::relay::{Relay, Request};
+use dns_camo::server::Server;
+
+fn key_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dns-camo-{}-{}", name, std::process::id()));
+    fs::write(&path, [0x42u8; 32]).unwrap();
+    path
+}
+
+// Server running `app` on the sessions after every query
+fn spawn_server(key: PathBuf, mut app: impl FnMut(&mut Server) + Send + 'static) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut server = Server::new(
+        DnsName::from_str("t.example.org").unwrap(),
+        DnsName::from_str("ns.example.org").unwrap(),
+        Keyring::single(Key::from_file(&key).unwrap()),
+    );
+    thread::spawn(move || {
+        let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).unwrap();
+            if let Ok(reply) = server.handle(&buf[..len]) {
+                socket.send_to(&reply, src).unwrap();
+            }
+            app(&mut server);
+        }
+    });
+    addr
+}
+
+// Echo every message, to be picked up by the client's polls
+fn echo(server: &mut Server) {
+    for id in server.session_ids() {
+        let stream = server.stream(id).unwrap();
+        while let Some(message) = stream.read_message() {
+            stream.write_message(&message);
+        }
+    }
+}
+
+// Positions of the label bytes in the question section and where it ends
+fn question_labels(msg: &[u8]) -> (Vec<usize>, usize) {
+    let count = u16::from_be_bytes([msg[4], msg[5]]);
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    for _ in 0..count {
+        loop {
+            let len = msg[pos] as usize;
+            if len == 0 {
+                pos += 1;
+                break;
+            }
+            if len & 0xC0 == 0xC0 {
+                pos += 2;
+                break;
+            }
+            labels.extend(pos + 1..pos + 1 + len);
+            pos += len + 1;
+        }
+        pos += 4;
+    }
+    (labels, pos)
+}
+
+// Position after the name at `pos`, however it ends
+fn skip_name(msg: &[u8], mut pos: usize) -> usize {
+    loop {
+        match msg[pos] as usize {
+            0 => return pos + 1,
+            len if len & 0xC0 == 0xC0 => return pos + 2,
+            len => pos += len + 1,
+        }
+    }
+}
+
+// Drop the additional records of a reply other than OPT, as resolvers don't
+// vouch for them
+fn strip_additional(reply: &mut Vec<u8>) {
+    let count = |msg: &[u8], at: usize| u16::from_be_bytes([msg[at], msg[at + 1]]) as usize;
+    let record_end = |msg: &[u8], pos: usize| {
+        let pos = skip_name(msg, pos);
+        (pos, pos + 10 + count(msg, pos + 8))
+    };
+    let mut pos = question_labels(reply).1;
+    for _ in 0..count(reply, 6) + count(reply, 8) {
+        pos = record_end(reply, pos).1;
+    }
+    let mut kept = Vec::new();
+    let mut kept_count: u16 = 0;
+    let mut next = pos;
+    for _ in 0..count(reply, 10) {
+        let (type_pos, end) = record_end(reply, next);
+        if reply[type_pos..type_pos + 2] == [0, 41] {
+            kept.extend_from_slice(&reply[next..end]);
+            kept_count += 1;
+        }
+        next = end;
+    }
+    reply.truncate(pos);
+    reply.extend_from_slice(&kept);
+    reply[10..12].copy_from_slice(&kept_count.to_be_bytes());
+}
+
+fn randomize_case(msg: &mut [u8], seed: u32) {
+    let mut state = seed;
+    for i in question_labels(msg).0 {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        if msg[i].is_ascii_alphabetic() && state & 0x10000 != 0 {
+            msg[i] ^= 0x20;
+        }
+    }
+}
+
+// Forward queries to `server`, dropping every `loss`th reply if not zero
+fn spawn_forwarder(server: SocketAddr, loss: usize) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+        let mut next_id: u16 = 0x7000;
+        for count in 1.. {
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            let original = buf[..len].to_vec();
+            assert_eq!(original[4..6], [0, 1], "more than one question");
+            let end = question_labels(&original).1;
+
+            // Ask twice with different case, as if the first attempt timed out
+            let mut answers = Vec::new();
+            for attempt in 0..2 {
+                let mut query = original.clone();
+                next_id = next_id.wrapping_add(1);
+                query[..2].copy_from_slice(&next_id.to_be_bytes());
+                randomize_case(&mut query, next_id as u32 + attempt);
+                upstream.send_to(&query, server).unwrap();
+                let (len, _) = upstream.recv_from(&mut buf).unwrap();
+                let reply = buf[..len].to_vec();
+                assert_eq!(reply[..2], query[..2]);
+                assert_eq!(reply[12..end], query[12..end], "question not echoed");
+                answers.push(reply);
+            }
+            // Both attempts must get the same answer data
+            assert_eq!(answers[0][end..], answers[1][end..]);
+
+            if loss != 0 && count % loss == 0 {
+                continue;
+            }
+            let mut reply = answers.swap_remove(0);
+            strip_additional(&mut reply);
+            reply[..2].copy_from_slice(&original[..2]);
+            reply[12..end].copy_from_slice(&original[12..end]);
+            socket.send_to(&reply, client).unwrap();
+        }
+    });
+    addr
+}
+
+fn exchange(resolver: SocketAddr, query: &mut Packet) -> Packet {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    socket
+        .send_to(query.serialize(0x1234).unwrap().as_raw_slice(), resolver)
+        .unwrap();
+    let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    let mut reply = Packet::new(false);
+    reply.deserialize(&buf[..len]).unwrap();
+    assert!(reply.is_response());
+    assert_eq!(reply.id(), 0x1234);
+    reply
+}
+
+// Send a message and poll for the reply, the way the client binary does
+fn run_client(client: &mut Client, resolver: SocketAddr, message: &[u8]) -> Vec<u8> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    client.stream().write_message(message);
+    let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+    for _ in 0..1000 {
+        if let Some(reply) = client.stream().read_message() {
+            return reply;
+        }
+        let query = client.next_query(Instant::now()).unwrap();
+        socket.send_to(&query, resolver).unwrap();
+        // Lost replies show up as timeouts, useless ones as errors
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            if client.handle_response(&buf[..len]).unwrap_or(true) {
+                break;
+            }
+        }
+    }
+    panic!("no reply");
+}
+
+#[test]
+fn tunnel_through_resolver() {
+    let key = key_file("resolver");
+    let resolver = spawn_forwarder(spawn_server(key.clone(), echo), 0);
+    let domain = DnsName::from_str("t.example.org").unwrap();
+
+    // Long enough to take several queries
+    let data: Vec<u8> = (0..200).collect();
+    let mut client = Client::new(domain, Key::from_file(&key).unwrap());
+    let reply = run_client(&mut client, resolver, &data);
+    assert_eq!(reply, data);
+    // The stream carries on with the next message
+    let reply = run_client(&mut client, resolver, b"again");
+    assert_eq!(reply, b"again");
+
+    // Data in addresses takes many answers, which all get through
+    for record_type in [RecordType::A, RecordType::AAAA] {
+        let domain = DnsName::from_str("t.example.org").unwrap();
+        let mut client = Client::new(domain, Key::from_file(&key).unwrap());
+        client.set_record_type(record_type);
+        let reply = run_client(&mut client, resolver, &data);
+        assert_eq!(reply, data);
+    }
+    fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn tunnel_over_lossy_path() {
+    let key = key_file("lossy");
+    let resolver = spawn_forwarder(spawn_server(key.clone(), echo), 3);
+    let domain = DnsName::from_str("t.example.org").unwrap();
+
+    let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let mut client = Client::new(domain, Key::from_file(&key).unwrap());
+    let reply = run_client(&mut client, resolver, &data);
+    assert_eq!(reply, data);
+    fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn zone_queries_through_resolver() {
+    let key = key_file("zone");
+    let resolver = spawn_forwarder(spawn_server(key.clone(), echo), 0);
+    let domain = DnsName::from_str("t.example.org").unwrap();
+
+    let mut soa = Packet::new(false);
+    soa.add_question(DnsName::from_str("T.Example.Org").unwrap(), RecordType::SOA);
+    let reply = exchange(resolver, &mut soa);
+    assert_eq!(reply.rcode(), ResponseCode::NoError);
+    assert!(!reply.extract_data(&domain).unwrap().is_empty());
+
+    // Names in the zone which don't carry tunnel data have no records
+    let mut junk = Packet::new(false);
+    junk.add_question(
+        DnsName::from_str("www.t.example.org").unwrap(),
+        RecordType::A,
+    );
+    let reply = exchange(resolver, &mut junk);
+    assert_eq!(reply.rcode(), ResponseCode::NoError);
+    assert!(reply.extract_data(&domain).unwrap().is_empty());
+
+    let mut other = Packet::new(false);
+    other.add_question(DnsName::from_str("example.com").unwrap(), RecordType::A);
+    let reply = exchange(resolver, &mut other);
+    assert_eq!(reply.rcode(), ResponseCode::Refused);
+    fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn forward_through_resolver() {
+    let key = key_file("forward");
+    // Connect sessions to what they ask for, the way the server binary does
+    let mut relays = HashMap::new();
+    let server = spawn_server(key.clone(), move |server: &mut Server| {
+        for id in server.session_ids() {
+            let stream = server.stream(id).unwrap();
+            let relay = match relays.entry(id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let Some(request) = stream.read_message() else {
+                        continue;
+                    };
+                    let Ok(Request::Connect(host, port)) = Request::deserialize(&request) else {
+                        panic!("unexpected request");
+                    };
+                    entry.insert(Relay::connect(host, port))
+                }
+            };
+            relay.wait(Duration::from_millis(10));
+            relay.pump(stream);
+        }
+    });
+    let resolver = spawn_forwarder(server, 0);
+    let domain = DnsName::from_str("t.example.org").unwrap();
+
+    // Service behind the server, answering in upper case
+    let service = TcpListener::bind("127.0.0.1:0").unwrap();
+    let service_port = service.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut socket, _) = service.accept().unwrap();
+        let mut data = Vec::new();
+        socket.read_to_end(&mut data).unwrap();
+        socket.write_all(&data.to_ascii_uppercase()).unwrap();
+    });
+
+    // Application connecting to the client's forwarded port
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut app = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let data: Vec<u8> = b"forwarded ".repeat(50);
+    app.write_all(&data).unwrap();
+    app.shutdown(Shutdown::Write).unwrap();
+
+    let mut client = Client::new(domain, Key::from_file(&key).unwrap());
+    client
+        .stream()
+        .write_message(&Request::Connect("127.0.0.1".into(), service_port).serialize());
+    let mut relay = Relay::accept(accepted).unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut buf = [0u8; MAX_EDNS_UDP_LEN];
+    for _ in 0..1000 {
+        relay.pump(client.stream());
+        assert!(relay.error().is_none());
+        if client.stream().is_closed() {
+            break;
+        }
+        let query = client.next_query(Instant::now()).unwrap();
+        socket.send_to(&query, resolver).unwrap();
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            if client.handle_response(&buf[..len]).unwrap_or(true) {
+                break;
+            }
+        }
+    }
+    assert!(client.stream().is_closed());
+    let mut reply = Vec::new();
+    app.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, data.to_ascii_uppercase());
+    fs::remove_file(key).unwrap();
+}